@@ -27,9 +27,11 @@ impl State {
         match event {
             Event::Expose(rects) => {
                 println!("expose: {:?}", rects);
+
+                self.window.as_ref().unwrap().request_frame();
             }
-            Event::Frame => {
-                println!("frame");
+            Event::Frame(frame_info) => {
+                println!("frame: {:?}", frame_info);
 
                 let window = self.window.as_ref().unwrap();
 
@@ -40,6 +42,14 @@ impl State {
 
                 window.present(Bitmap::new(&self.framebuffer, self.width, self.height));
             }
+            Event::Resize(size) => {
+                println!("resize: {:?}", size);
+
+                self.window.as_ref().unwrap().request_frame();
+            }
+            Event::Visible(visible) => {
+                println!("visible: {:?}", visible);
+            }
             Event::GainFocus => {
                 println!("gain focus");
             }
@@ -55,18 +65,38 @@ impl State {
             Event::MouseMove(pos) => {
                 println!("mouse move: {:?}", pos);
             }
-            Event::MouseDown(btn) => {
-                println!("mouse down: {:?}", btn);
+            Event::RawMouseMotion(delta) => {
+                println!("raw mouse motion: {:?}", delta);
+            }
+            Event::MouseDown(mouse_event) => {
+                println!("mouse down: {:?}", mouse_event);
+                return Response::Capture;
+            }
+            Event::MouseUp(mouse_event) => {
+                println!("mouse up: {:?}", mouse_event);
+                return Response::Capture;
+            }
+            Event::Scroll(scroll_event) => {
+                println!("scroll: {:?}", scroll_event);
                 return Response::Capture;
             }
-            Event::MouseUp(btn) => {
-                println!("mouse up: {:?}", btn);
+            Event::KeyDown(key) => {
+                println!("key down: {:?}", key);
                 return Response::Capture;
             }
-            Event::Scroll(delta) => {
-                println!("scroll: {:?}", delta);
+            Event::KeyUp(key) => {
+                println!("key up: {:?}", key);
                 return Response::Capture;
             }
+            Event::TextInput(text) => {
+                println!("text input: {:?}", text);
+            }
+            Event::ImePreedit(text, caret) => {
+                println!("ime preedit: {:?} {:?}", text, caret);
+            }
+            Event::ImeCommit(text) => {
+                println!("ime commit: {:?}", text);
+            }
             Event::Close => {
                 self.event_loop.exit();
             }
@@ -98,6 +128,7 @@ fn main() {
         .unwrap();
 
     window.show();
+    window.request_frame();
 
     state.borrow_mut().window = Some(window);
 
@@ -15,7 +15,7 @@ struct ParentState {
 impl ParentState {
     fn handle_event(&mut self, event: Event) -> Response {
         match event {
-            Event::Frame => {
+            Event::Frame(_) => {
                 let window = &self.window.as_ref().unwrap();
 
                 let scale = window.scale();
@@ -25,6 +25,9 @@ impl ParentState {
                 self.framebuffer.resize(width * height, 0xFF00FFFF);
                 window.present(Bitmap::new(&self.framebuffer, width, height));
             }
+            Event::Expose(_) | Event::Resize(_) => {
+                self.window.as_ref().unwrap().request_frame();
+            }
             Event::Close => {
                 self.event_loop.exit();
             }
@@ -43,7 +46,7 @@ struct ChildState {
 impl ChildState {
     fn handle_event(&mut self, event: Event) -> Response {
         match event {
-            Event::Frame => {
+            Event::Frame(_) => {
                 let window = &self.window.as_ref().unwrap();
 
                 let scale = window.scale();
@@ -53,6 +56,9 @@ impl ChildState {
                 self.framebuffer.resize(width * height, 0xFFFF00FF);
                 window.present(Bitmap::new(&self.framebuffer, width, height));
             }
+            Event::Expose(_) | Event::Resize(_) => {
+                self.window.as_ref().unwrap().request_frame();
+            }
             _ => {}
         }
 
@@ -79,6 +85,7 @@ fn main() {
         .unwrap();
 
     window.show();
+    window.request_frame();
 
     let parent_window_raw = window.as_raw().unwrap();
     parent_state.borrow_mut().window = Some(window);
@@ -105,6 +112,7 @@ fn main() {
         .unwrap();
 
     window.show();
+    window.request_frame();
 
     child_state.borrow_mut().window = Some(window);
 
@@ -4,18 +4,22 @@ use std::ffi::c_void;
 use std::panic::{self, AssertUnwindSafe};
 use std::ptr::{self, NonNull};
 use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use objc2_app_kit::NSScreen;
 use objc2_core_foundation::{
     kCFRunLoopCommonModes, CFRetained, CFRunLoop, CFRunLoopSource, CFRunLoopSourceContext,
 };
 use objc2_core_graphics::CGDirectDisplayID;
-use objc2_core_video::{kCVReturnSuccess, CVDisplayLink, CVOptionFlags, CVReturn, CVTimeStamp};
+use objc2_core_video::{
+    kCVReturnSuccess, CVDisplayLink, CVGetHostClockFrequency, CVOptionFlags, CVReturn, CVTimeStamp,
+};
 use objc2_foundation::{ns_string, NSNumber};
 
 use super::event_loop::EventLoopState;
 use super::window::View;
-use crate::Event;
+use crate::{Event, FrameInfo};
 
 fn display_from_screen(screen: &NSScreen) -> Option<CGDirectDisplayID> {
     let number = screen.deviceDescription().objectForKey(ns_string!("NSScreenNumber"))?;
@@ -28,16 +32,42 @@ fn display_from_view(view: &View) -> Option<CGDirectDisplayID> {
     display_from_screen(&*screen)
 }
 
+fn frame_info(output_time: &CVTimeStamp) -> FrameInfo {
+    let time_scale = output_time.videoTimeScale.max(1) as f64;
+    let refresh_period = output_time.videoRefreshPeriod.max(1);
+
+    FrameInfo {
+        target_time: Duration::from_secs_f64(
+            output_time.hostTime as f64 / CVGetHostClockFrequency(),
+        ),
+        presented_time: None,
+        refresh_interval: Duration::from_secs_f64(refresh_period as f64 / time_scale),
+        sequence: (output_time.videoTime / refresh_period) as u64,
+    }
+}
+
+// Passed to the display link callback, which runs on its own thread.
+struct CallbackContext {
+    source: *const CFRunLoopSource,
+    output_time: Arc<Mutex<Option<CVTimeStamp>>>,
+}
+
 #[allow(non_snake_case)]
 extern "C-unwind" fn callback(
     _displayLink: NonNull<CVDisplayLink>,
     _inNow: NonNull<CVTimeStamp>,
-    _inOutputTime: NonNull<CVTimeStamp>,
+    inOutputTime: NonNull<CVTimeStamp>,
     _flagsIn: CVOptionFlags,
     _flagsOut: NonNull<CVOptionFlags>,
     displayLinkContext: *mut c_void,
 ) -> CVReturn {
-    let source = unsafe { &*(displayLinkContext as *const CFRunLoopSource) };
+    let context = unsafe { &*(displayLinkContext as *const CallbackContext) };
+
+    if let Ok(mut output_time) = context.output_time.lock() {
+        *output_time = Some(unsafe { *inOutputTime.as_ptr() });
+    }
+
+    let source = unsafe { &*context.source };
     source.signal();
 
     let run_loop = CFRunLoop::main().unwrap();
@@ -70,6 +100,12 @@ extern "C-unwind" fn perform(info: *mut c_void) {
         return;
     };
 
+    let output_time = state.output_time.lock().ok().and_then(|output_time| *output_time);
+    let Some(output_time) = output_time else {
+        return;
+    };
+    let frame_info = frame_info(&output_time);
+
     let result = panic::catch_unwind(AssertUnwindSafe(|| {
         let windows: Vec<*const View> = event_loop_state.windows.borrow().keys().copied().collect();
         for ptr in windows {
@@ -77,8 +113,8 @@ extern "C-unwind" fn perform(info: *mut c_void) {
             if let Some(window_state) = window_state {
                 if let Some(view) = window_state.view() {
                     let display = display_from_view(&*view);
-                    if display == Some(state.display_id) {
-                        window_state.handle_event(Event::Frame);
+                    if display == Some(state.display_id) && window_state.take_frame() {
+                        window_state.handle_event(Event::Frame(frame_info));
                     }
                 }
             }
@@ -93,11 +129,15 @@ extern "C-unwind" fn perform(info: *mut c_void) {
 struct DisplayState {
     display_id: CGDirectDisplayID,
     event_loop_state: Weak<EventLoopState>,
+    // The time of the next refresh, as reported by the most recent callback.
+    output_time: Arc<Mutex<Option<CVTimeStamp>>>,
 }
 
 struct Display {
     link: CFRetained<CVDisplayLink>,
     source: CFRetained<CFRunLoopSource>,
+    // Kept alive until the display link is stopped.
+    _callback_context: Box<CallbackContext>,
 }
 
 impl Display {
@@ -105,9 +145,12 @@ impl Display {
         event_loop_state: &Rc<EventLoopState>,
         display_id: CGDirectDisplayID,
     ) -> Option<Display> {
+        let output_time = Arc::new(Mutex::new(None));
+
         let state = Rc::new(DisplayState {
             display_id,
             event_loop_state: Rc::downgrade(event_loop_state),
+            output_time: output_time.clone(),
         });
 
         let mut context = CFRunLoopSourceContext {
@@ -128,7 +171,10 @@ impl Display {
         let run_loop = CFRunLoop::main().unwrap();
         run_loop.add_source(Some(&source), unsafe { kCFRunLoopCommonModes });
 
-        let source_ptr = CFRetained::as_ptr(&source).as_ptr();
+        let callback_context = Box::new(CallbackContext {
+            source: CFRetained::as_ptr(&source).as_ptr(),
+            output_time,
+        });
 
         let mut link = ptr::null_mut();
 
@@ -145,11 +191,16 @@ impl Display {
 
         #[allow(deprecated)]
         unsafe {
-            link.set_output_callback(Some(callback), source_ptr as *mut c_void);
+            let context_ptr = &*callback_context as *const CallbackContext;
+            link.set_output_callback(Some(callback), context_ptr as *mut c_void);
             link.start();
         }
 
-        Some(Display { link, source })
+        Some(Display {
+            link,
+            source,
+            _callback_context: callback_context,
+        })
     }
 }
 
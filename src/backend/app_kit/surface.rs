@@ -11,7 +11,7 @@ use objc2_io_surface::{
 use libc::kern_return_t;
 
 use super::OsError;
-use crate::{Bitmap, Error, Result};
+use crate::{Bitmap, Error, OwnedBitmap, PixelFormat, Result};
 
 #[allow(non_upper_case_globals)]
 const kIOSurfaceSuccess: kern_return_t = 0;
@@ -78,27 +78,54 @@ impl Surface {
         self.height
     }
 
-    pub fn update(&self, bitmap: Bitmap) {
+    pub fn update(&self, bitmap: Bitmap, format: PixelFormat) {
         let ret = unsafe { self.surface.lock(IOSurfaceLockOptions::empty(), ptr::null_mut()) };
         if ret != kIOSurfaceSuccess {
             return;
         }
 
         let addr = self.surface.base_address().as_ptr();
-        let len = self.stride * self.height;
-        let buffer = unsafe { slice::from_raw_parts_mut(addr as *mut u32, len) };
+        let len = self.stride * self.height * BYTES_PER_ELEMENT;
+        let buffer = unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) };
 
         let copy_width = bitmap.width().min(self.width);
         let copy_height = bitmap.height().min(self.height);
 
-        for row in 0..copy_height {
-            let src = &bitmap.data()[row * bitmap.width()..row * bitmap.width() + copy_width];
-            let dst = &mut buffer[row * self.stride..row * self.stride + copy_width];
-            dst.copy_from_slice(src);
-        }
+        // The surface is 32BGRA, which on Apple platforms has the same layout as Xrgb and Argb.
+        bitmap.crop(0, 0, copy_width, copy_height).copy_to(
+            buffer,
+            self.stride * BYTES_PER_ELEMENT,
+            format,
+        );
 
         unsafe {
             self.surface.unlock(IOSurfaceLockOptions::empty(), ptr::null_mut());
         }
     }
+
+    pub fn read(&self, format: PixelFormat) -> Result<OwnedBitmap> {
+        let ret = unsafe { self.surface.lock(IOSurfaceLockOptions::ReadOnly, ptr::null_mut()) };
+        if ret != kIOSurfaceSuccess {
+            return Err(Error::Os(OsError::Other("could not lock IOSurface")));
+        }
+
+        let addr = self.surface.base_address().as_ptr();
+        let len = self.stride * self.height * BYTES_PER_ELEMENT;
+        let buffer = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+
+        let bitmap = Bitmap::from_bytes(
+            buffer,
+            self.width,
+            self.height,
+            self.stride * BYTES_PER_ELEMENT,
+            format,
+        );
+        let owned = OwnedBitmap::from_bitmap(bitmap, format);
+
+        unsafe {
+            self.surface.unlock(IOSurfaceLockOptions::ReadOnly, ptr::null_mut());
+        }
+
+        Ok(owned)
+    }
 }
@@ -4,6 +4,7 @@ use std::ffi::CString;
 use std::ops::{Deref, DerefMut};
 use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
+use std::time::Duration;
 
 use objc2::declare::ClassBuilder;
 use objc2::encode::Encoding;
@@ -13,17 +14,22 @@ use objc2::runtime::{AnyClass, AnyObject, Bool, MessageReceiver, Sel};
 use objc2::{class, msg_send, sel, AnyThread, ClassType, Message, RefEncode};
 
 use objc2_app_kit::{
-    NSBackingStoreType, NSCursor, NSEvent, NSScreen, NSTrackingArea, NSTrackingAreaOptions, NSView,
-    NSWindow, NSWindowStyleMask,
+    NSBackingStoreType, NSColor, NSCursor, NSEvent, NSEventModifierFlags, NSEventPhase, NSScreen,
+    NSTrackingArea, NSTrackingAreaOptions, NSView, NSWindow, NSWindowOcclusionState,
+    NSWindowStyleMask,
 };
 use objc2_foundation::{NSInteger, NSPoint, NSRect, NSSize, NSString};
-use objc2_quartz_core::{kCAFilterNearest, kCAGravityBottomLeft, CALayer};
+use objc2_quartz_core::{
+    kCAFilterLinear, kCAFilterNearest, kCAGravityBottomLeft, kCAGravityResize,
+    kCAGravityResizeAspect, kCAGravityResizeAspectFill, CALayer,
+};
 
 use super::surface::Surface;
 use super::OsError;
 use crate::{
-    Bitmap, Cursor, Error, Event, EventLoop, MouseButton, Point, RawWindow, Rect, Response, Result,
-    Size, WindowOptions,
+    Bitmap, BitmapMut, Cursor, Error, Event, EventLoop, Modifiers, MouseButton, MouseEvent,
+    OwnedBitmap, PixelFormat, Point, RawWindow, Rect, Response, Result, Scaling, ScrollDelta,
+    ScrollEvent, ScrollPhase, Size, WindowOptions,
 };
 
 fn class_name() -> CString {
@@ -51,6 +57,47 @@ fn mouse_button_from_number(button_number: NSInteger) -> Option<MouseButton> {
     }
 }
 
+fn modifiers_from_event(event: &NSEvent) -> Modifiers {
+    let flags = event.modifierFlags();
+
+    let mut modifiers = Modifiers::empty();
+
+    if flags.contains(NSEventModifierFlags::Shift) {
+        modifiers |= Modifiers::SHIFT;
+    }
+    if flags.contains(NSEventModifierFlags::Control) {
+        modifiers |= Modifiers::CONTROL;
+    }
+    if flags.contains(NSEventModifierFlags::Option) {
+        modifiers |= Modifiers::ALT;
+    }
+    if flags.contains(NSEventModifierFlags::Command) {
+        modifiers |= Modifiers::META;
+    }
+
+    modifiers
+}
+
+// Momentum scrolling after the user lifts their fingers is reported as a separate gesture.
+fn scroll_phase_from_event(event: &NSEvent) -> Option<ScrollPhase> {
+    let phase = event.phase();
+    let phase = if phase.is_empty() {
+        event.momentumPhase()
+    } else {
+        phase
+    };
+
+    if phase.contains(NSEventPhase::Began) {
+        Some(ScrollPhase::Begin)
+    } else if phase.contains(NSEventPhase::Changed) {
+        Some(ScrollPhase::Update)
+    } else if phase.intersects(NSEventPhase::Ended | NSEventPhase::Cancelled) {
+        Some(ScrollPhase::End)
+    } else {
+        None
+    }
+}
+
 fn set_contents_opaque(layer: &CALayer, contents_opaque: bool) {
     unsafe {
         let () = msg_send![layer, setContentsOpaque: contents_opaque];
@@ -104,10 +151,26 @@ impl View {
                 sel!(acceptsFirstMouse:),
                 Self::accepts_first_mouse as unsafe extern "C" fn(_, _, _) -> _,
             );
+            builder.add_method(
+                sel!(acceptsFirstResponder),
+                Self::accepts_first_responder as unsafe extern "C" fn(_, _) -> _,
+            );
+            builder.add_method(
+                sel!(becomeFirstResponder),
+                Self::become_first_responder as unsafe extern "C" fn(_, _) -> _,
+            );
+            builder.add_method(
+                sel!(resignFirstResponder),
+                Self::resign_first_responder as unsafe extern "C" fn(_, _) -> _,
+            );
             builder.add_method(
                 sel!(isFlipped),
                 Self::is_flipped as unsafe extern "C" fn(_, _) -> _,
             );
+            builder.add_method(
+                sel!(setFrameSize:),
+                Self::set_frame_size as unsafe extern "C" fn(_, _, _),
+            );
             builder.add_method(
                 sel!(mouseEntered:),
                 Self::mouse_entered as unsafe extern "C" fn(_, _, _),
@@ -203,10 +266,46 @@ impl View {
         Bool::YES
     }
 
+    unsafe extern "C" fn accepts_first_responder(&self, _: Sel) -> Bool {
+        Bool::YES
+    }
+
+    unsafe extern "C" fn become_first_responder(&self, _: Sel) -> Bool {
+        self.catch_unwind(|| {
+            self.state().handle_event(Event::GainFocus);
+        });
+
+        Bool::YES
+    }
+
+    unsafe extern "C" fn resign_first_responder(&self, _: Sel) -> Bool {
+        self.catch_unwind(|| {
+            self.state().handle_event(Event::LoseFocus);
+        });
+
+        Bool::YES
+    }
+
     unsafe extern "C" fn is_flipped(&self, _: Sel) -> Bool {
         Bool::YES
     }
 
+    // Both resizing the window and the host resizing an embedded view end up here.
+    unsafe extern "C" fn set_frame_size(&self, _: Sel, size: NSSize) {
+        let old_size = self.frame().size;
+
+        let () = msg_send![super(self, NSView::class()), setFrameSize: size];
+
+        // The frame is set during initialization, before the window state exists.
+        if self.state_ivar().get().is_null() || old_size == size {
+            return;
+        }
+
+        self.catch_unwind(|| {
+            self.state().handle_event(Event::Resize(Size::new(size.width, size.height)));
+        });
+    }
+
     unsafe extern "C" fn mouse_entered(&self, _: Sel, _event: Option<&NSEvent>) {
         self.catch_unwind(|| {
             self.state().handle_event(Event::MouseEnter);
@@ -233,9 +332,26 @@ impl View {
         });
     }
 
+    fn mouse_event(&self, button: MouseButton, event: &NSEvent) -> MouseEvent {
+        let point = self.convertPoint_fromView(event.locationInWindow(), None);
+
+        MouseEvent {
+            button,
+            position: Point::new(point.x, point.y),
+            modifiers: modifiers_from_event(event),
+            click_count: event.clickCount().max(1) as u32,
+            time: Duration::from_secs_f64(event.timestamp()),
+        }
+    }
+
     unsafe extern "C" fn mouse_down(&self, _: Sel, event: Option<&NSEvent>) {
         self.catch_unwind(|| {
-            let result = self.state().handle_event(Event::MouseDown(MouseButton::Left));
+            let Some(event) = event else {
+                return;
+            };
+
+            let mouse_event = self.mouse_event(MouseButton::Left, event);
+            let result = self.state().handle_event(Event::MouseDown(mouse_event));
 
             if result != Some(Response::Capture) {
                 let () = msg_send![super(self, NSView::class()), mouseDown: event];
@@ -245,7 +361,12 @@ impl View {
 
     unsafe extern "C" fn mouse_up(&self, _: Sel, event: Option<&NSEvent>) {
         self.catch_unwind(|| {
-            let result = self.state().handle_event(Event::MouseUp(MouseButton::Left));
+            let Some(event) = event else {
+                return;
+            };
+
+            let mouse_event = self.mouse_event(MouseButton::Left, event);
+            let result = self.state().handle_event(Event::MouseUp(mouse_event));
 
             if result != Some(Response::Capture) {
                 let () = msg_send![super(self, NSView::class()), mouseUp: event];
@@ -255,7 +376,12 @@ impl View {
 
     unsafe extern "C" fn right_mouse_down(&self, _: Sel, event: Option<&NSEvent>) {
         self.catch_unwind(|| {
-            let result = self.state().handle_event(Event::MouseDown(MouseButton::Right));
+            let Some(event) = event else {
+                return;
+            };
+
+            let mouse_event = self.mouse_event(MouseButton::Right, event);
+            let result = self.state().handle_event(Event::MouseDown(mouse_event));
 
             if result != Some(Response::Capture) {
                 let () = msg_send![super(self, NSView::class()), rightMouseDown: event];
@@ -265,7 +391,12 @@ impl View {
 
     unsafe extern "C" fn right_mouse_up(&self, _: Sel, event: Option<&NSEvent>) {
         self.catch_unwind(|| {
-            let result = self.state().handle_event(Event::MouseUp(MouseButton::Right));
+            let Some(event) = event else {
+                return;
+            };
+
+            let mouse_event = self.mouse_event(MouseButton::Right, event);
+            let result = self.state().handle_event(Event::MouseUp(mouse_event));
 
             if result != Some(Response::Capture) {
                 let () = msg_send![super(self, NSView::class()), rightMouseUp: event];
@@ -281,7 +412,8 @@ impl View {
 
             let button_number = event.buttonNumber();
             let result = if let Some(button) = mouse_button_from_number(button_number) {
-                self.state().handle_event(Event::MouseDown(button))
+                let mouse_event = self.mouse_event(button, event);
+                self.state().handle_event(Event::MouseDown(mouse_event))
             } else {
                 None
             };
@@ -300,7 +432,8 @@ impl View {
 
             let button_number = event.buttonNumber();
             let result = if let Some(button) = mouse_button_from_number(button_number) {
-                self.state().handle_event(Event::MouseUp(button))
+                let mouse_event = self.mouse_event(button, event);
+                self.state().handle_event(Event::MouseUp(mouse_event))
             } else {
                 None
             };
@@ -317,14 +450,21 @@ impl View {
                 return;
             };
 
-            let dx = event.scrollingDeltaX();
-            let dy = event.scrollingDeltaY();
+            let delta = Point::new(event.scrollingDeltaX(), event.scrollingDeltaY());
             let delta = if event.hasPreciseScrollingDeltas() {
-                Point::new(dx, dy)
+                ScrollDelta::Pixels(delta)
             } else {
-                Point::new(32.0 * dx, 32.0 * dy)
+                ScrollDelta::Lines(delta)
+            };
+            let point = self.convertPoint_fromView(event.locationInWindow(), None);
+            let scroll_event = ScrollEvent {
+                delta,
+                phase: scroll_phase_from_event(event),
+                position: Point::new(point.x, point.y),
+                modifiers: modifiers_from_event(event),
+                time: Duration::from_secs_f64(event.timestamp()),
             };
-            let result = self.state().handle_event(Event::Scroll(delta));
+            let result = self.state().handle_event(Event::Scroll(scroll_event));
 
             if result != Some(Response::Capture) {
                 let () = msg_send![super(self, NSView::class()), scrollWheel: event];
@@ -368,7 +508,12 @@ pub struct WindowState {
     window: RefCell<Option<Retained<NSWindow>>>,
     layer: RefCell<Option<Retained<CALayer>>>,
     surface: RefCell<Option<Surface>>,
+    transparent: bool,
+    // Used by with_buffer, which draws into it and then presents it.
+    buffer: RefCell<Vec<u32>>,
     cursor: Cell<Cursor>,
+    frame_requested: Cell<bool>,
+    continuous_frames: Cell<bool>,
     event_loop: EventLoop,
     handler: RefCell<Box<dyn FnMut(Event) -> Response>>,
 }
@@ -453,7 +598,11 @@ impl WindowState {
                 window: RefCell::new(None),
                 layer: RefCell::new(None),
                 surface: RefCell::new(None),
+                transparent: options.transparent,
+                buffer: RefCell::new(Vec::new()),
                 cursor: Cell::new(Cursor::Arrow),
+                frame_requested: Cell::new(false),
+                continuous_frames: Cell::new(false),
                 event_loop: event_loop.clone(),
                 handler: RefCell::new(Box::new(handler)),
             });
@@ -494,10 +643,13 @@ impl WindowState {
                     NSSize::new(options.size.width, options.size.height),
                 );
 
-                let style_mask = NSWindowStyleMask::Titled
+                let mut style_mask = NSWindowStyleMask::Titled
                     | NSWindowStyleMask::Closable
-                    | NSWindowStyleMask::Miniaturizable
-                    | NSWindowStyleMask::Resizable;
+                    | NSWindowStyleMask::Miniaturizable;
+
+                if options.resizable {
+                    style_mask |= NSWindowStyleMask::Resizable;
+                }
 
                 let window = unsafe {
                     NSWindow::initWithContentRect_styleMask_backing_defer(
@@ -517,6 +669,18 @@ impl WindowState {
                     let () = msg_send![&*window, setDelegate: &*view];
                     window.setContentView(Some(&view));
 
+                    if let Some(min_size) = options.min_size {
+                        window.setContentMinSize(NSSize::new(min_size.width, min_size.height));
+                    }
+                    if let Some(max_size) = options.max_size {
+                        window.setContentMaxSize(NSSize::new(max_size.width, max_size.height));
+                    }
+
+                    if options.transparent {
+                        window.setOpaque(false);
+                        window.setBackgroundColor(Some(&NSColor::clearColor()));
+                    }
+
                     if options.position.is_none() {
                         window.center();
                     }
@@ -526,8 +690,8 @@ impl WindowState {
             }
 
             let layer = CALayer::layer();
-            layer.setOpaque(true);
-            set_contents_opaque(&layer, true);
+            layer.setOpaque(!options.transparent);
+            set_contents_opaque(&layer, !options.transparent);
             layer.setContentsGravity(unsafe { kCAGravityBottomLeft });
             layer.setMagnificationFilter(unsafe { kCAFilterNearest });
             layer.setContentsScale(state.scale());
@@ -582,6 +746,18 @@ impl WindowState {
         })
     }
 
+    pub fn set_size(&self, size: Size) {
+        autoreleasepool(|_| {
+            let size = NSSize::new(size.width, size.height);
+
+            if let Some(window) = self.window() {
+                window.setContentSize(size);
+            } else if let Some(view) = self.view() {
+                view.setFrameSize(size);
+            }
+        })
+    }
+
     pub fn scale(&self) -> f64 {
         autoreleasepool(|_| {
             let mtm = self.event_loop.state.mtm;
@@ -621,7 +797,7 @@ impl WindowState {
                 surface
             });
 
-            surface.update(bitmap);
+            surface.update(bitmap, self.pixel_format());
 
             if let Some(layer) = &*self.layer.borrow() {
                 set_contents_changed(layer);
@@ -629,10 +805,91 @@ impl WindowState {
         })
     }
 
+    // Pixels are converted to this format when presenting. Xrgb avoids a conversion for opaque
+    // windows, since their alpha is ignored anyway.
+    fn pixel_format(&self) -> PixelFormat {
+        if self.transparent {
+            PixelFormat::Argb
+        } else {
+            PixelFormat::Xrgb
+        }
+    }
+
+    pub fn with_buffer<F: FnOnce(&mut BitmapMut)>(&self, f: F) {
+        let size_physical = self.size().scale(self.scale());
+        let width = size_physical.width.round() as usize;
+        let height = size_physical.height.round() as usize;
+
+        let mut data = self.buffer.take();
+        data.resize(width * height, 0);
+        let mut bitmap = BitmapMut::with_format(&mut data, width, height, self.pixel_format());
+        f(&mut bitmap);
+        self.present(bitmap.as_bitmap());
+        self.buffer.replace(data);
+    }
+
+    // The layer's contents can't be read back, but the surface holds the last presented bitmap.
+    pub fn capture(&self) -> Result<OwnedBitmap> {
+        match &*self.surface.borrow() {
+            Some(surface) => surface.read(self.pixel_format()),
+            None => Err(Error::Os(OsError::Other("nothing has been presented"))),
+        }
+    }
+
+    // present_partial presents the whole bitmap.
+    pub fn scroll_contents(&self, _rect: Rect, _delta: Point) {}
+
+    pub fn set_scaling(&self, scaling: Scaling) {
+        autoreleasepool(|_| {
+            if let Some(layer) = &*self.layer.borrow() {
+                let (gravity, filter) = unsafe {
+                    match scaling {
+                        Scaling::None => (kCAGravityBottomLeft, kCAFilterNearest),
+                        Scaling::Stretch => (kCAGravityResize, kCAFilterLinear),
+                        Scaling::Fit => (kCAGravityResizeAspect, kCAFilterLinear),
+                        Scaling::Fill => (kCAGravityResizeAspectFill, kCAFilterLinear),
+                    }
+                };
+                layer.setContentsGravity(gravity);
+                layer.setMagnificationFilter(filter);
+            }
+        })
+    }
+
     pub fn present_partial(&self, bitmap: Bitmap, _rects: &[Rect]) {
         self.present(bitmap);
     }
 
+    pub fn request_frame(&self) {
+        self.frame_requested.set(true);
+    }
+
+    pub fn set_continuous_frames(&self, continuous: bool) {
+        self.continuous_frames.set(continuous);
+    }
+
+    // Called on every display link callback. Returns whether Event::Frame should be sent. A
+    // request made while the view is hidden or its window is occluded is kept until it is visible
+    // again.
+    pub fn take_frame(&self) -> bool {
+        let visible = autoreleasepool(|_| {
+            let Some(view) = self.view() else {
+                return false;
+            };
+            let Some(window) = view.window() else {
+                return false;
+            };
+
+            !view.isHiddenOrHasHiddenAncestor()
+                && window.occlusionState().contains(NSWindowOcclusionState::Visible)
+        });
+        if !visible {
+            return false;
+        }
+
+        self.frame_requested.take() || self.continuous_frames.get()
+    }
+
     pub fn set_cursor(&self, cursor: Cursor) {
         autoreleasepool(|_| {
             self.cursor.set(cursor);
@@ -642,6 +899,38 @@ impl WindowState {
 
     pub fn set_mouse_position(&self, _position: Point) {}
 
+    pub fn focus(&self) {
+        autoreleasepool(|_| {
+            if let Some(view) = self.view() {
+                if let Some(window) = view.window() {
+                    window.makeFirstResponder(Some(&view));
+                }
+            }
+        })
+    }
+
+    pub fn has_focus(&self) -> bool {
+        autoreleasepool(|_| {
+            if let Some(view) = self.view() {
+                if let Some(window) = view.window() {
+                    if let Some(responder) = window.firstResponder() {
+                        return window.isKeyWindow()
+                            && Retained::as_ptr(&responder) as *const c_void
+                                == Retained::as_ptr(&view) as *const c_void;
+                    }
+                }
+            }
+
+            false
+        })
+    }
+
+    pub fn lock_pointer(&self) {}
+
+    pub fn unlock_pointer(&self) {}
+
+    pub fn set_ime_cursor_area(&self, _rect: Rect) {}
+
     pub fn close(&self) {
         autoreleasepool(|_| {
             if let Some(window) = self.window.take() {
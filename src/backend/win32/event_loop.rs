@@ -20,6 +20,7 @@ use super::timer::Timers;
 use super::vsync::VsyncThreads;
 use super::window::{self, WindowState};
 use super::{class_name, hinstance, to_wstring, WM_USER_VBLANK};
+use crate::timestamp::Timestamps;
 use crate::{Error, EventLoopMode, EventLoopOptions, Result};
 
 fn register_message_class() -> Result<PCWSTR> {
@@ -128,6 +129,9 @@ pub struct EventLoopState {
     pub dpi: DpiFns,
     pub timers: Timers,
     pub vsync_threads: VsyncThreads,
+    // Extends message times, which wrap around after about 49.7 days, to 64 bits. Fed from every
+    // mouse message.
+    pub timestamps: Timestamps,
     pub windows: RefCell<HashMap<isize, Rc<WindowState>>>,
 }
 
@@ -200,6 +204,7 @@ impl EventLoopState {
             dpi,
             timers,
             vsync_threads,
+            timestamps: Timestamps::new(),
             windows: RefCell::new(HashMap::new()),
         });
 
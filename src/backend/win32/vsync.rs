@@ -1,8 +1,9 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use windows::Win32::Foundation::{HWND, LPARAM, WPARAM};
 use windows::Win32::Graphics::Dxgi::{
@@ -13,11 +14,22 @@ use windows::Win32::UI::WindowsAndMessaging::PostMessageW;
 
 use super::event_loop::EventLoopState;
 use super::WM_USER_VBLANK;
-use crate::Event;
+use crate::{Event, FrameInfo};
+
+// Assumed until the refresh interval has been measured.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_micros(16_667);
+
+#[derive(Copy, Clone)]
+struct Vblank {
+    count: u64,
+    time: Instant,
+    interval: Duration,
+}
 
 struct ThreadState {
     pending: AtomicBool,
     halt: AtomicBool,
+    last_vblank: Mutex<Vblank>,
 }
 
 struct Thread {
@@ -30,6 +42,11 @@ impl Thread {
         let state = Arc::new(ThreadState {
             pending: AtomicBool::new(false),
             halt: AtomicBool::new(false),
+            last_vblank: Mutex::new(Vblank {
+                count: 0,
+                time: Instant::now(),
+                interval: DEFAULT_REFRESH_INTERVAL,
+            }),
         });
 
         let handle = thread::spawn({
@@ -41,6 +58,15 @@ impl Thread {
                             return;
                         }
 
+                        let now = Instant::now();
+                        if let Ok(mut last_vblank) = state.last_vblank.lock() {
+                            if last_vblank.count > 0 {
+                                last_vblank.interval = now - last_vblank.time;
+                            }
+                            last_vblank.count += 1;
+                            last_vblank.time = now;
+                        }
+
                         let was_pending = state.pending.swap(true, Ordering::Relaxed);
 
                         // Only deliver a vblank message if the previous one has been acknowledged.
@@ -63,12 +89,15 @@ impl Thread {
 
 pub struct VsyncThreads {
     threads: RefCell<HashMap<isize, Thread>>,
+    // The starting point for frame timestamps.
+    start: Instant,
 }
 
 impl VsyncThreads {
     pub fn new() -> VsyncThreads {
         VsyncThreads {
             threads: RefCell::new(HashMap::new()),
+            start: Instant::now(),
         }
     }
 
@@ -95,13 +124,32 @@ impl VsyncThreads {
     }
 
     pub fn handle_vblank(&self, event_loop_state: &EventLoopState, monitor: HMONITOR) {
+        let last_vblank = self
+            .threads
+            .borrow()
+            .get(&monitor.0)
+            .and_then(|thread| thread.state.last_vblank.lock().ok().map(|vblank| *vblank));
+        let Some(last_vblank) = last_vblank else {
+            return;
+        };
+
+        // A bitmap presented now appears at the next vblank.
+        let frame_info = FrameInfo {
+            target_time: (last_vblank.time + last_vblank.interval) - self.start,
+            presented_time: None,
+            refresh_interval: last_vblank.interval,
+            sequence: last_vblank.count + 1,
+        };
+
         let windows: Vec<isize> = event_loop_state.windows.borrow().keys().copied().collect();
         for hwnd in windows {
             let window_monitor = unsafe { MonitorFromWindow(HWND(hwnd), MONITOR_DEFAULTTONEAREST) };
             if window_monitor == monitor {
                 let window_state = event_loop_state.windows.borrow().get(&hwnd).cloned();
                 if let Some(window_state) = window_state {
-                    window_state.handle_event(Event::Frame);
+                    if window_state.take_frame() {
+                        window_state.handle_event(Event::Frame(frame_info));
+                    }
                 }
             }
         }
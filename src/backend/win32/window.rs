@@ -11,19 +11,23 @@ use windows::Win32::Foundation::{FALSE, HINSTANCE, HWND, LPARAM, LRESULT, POINT,
 use windows::Win32::Graphics::Gdi::{self as gdi, HBRUSH};
 use windows::Win32::UI::Controls::{HOVER_DEFAULT, WM_MOUSELEAVE};
 use windows::Win32::UI::Input::KeyboardAndMouse::{
-    ReleaseCapture, SetCapture, TrackMouseEvent, TME_LEAVE, TRACKMOUSEEVENT,
+    GetDoubleClickTime, GetFocus, GetKeyState, ReleaseCapture, SetCapture, SetFocus,
+    TrackMouseEvent, TME_LEAVE, TRACKMOUSEEVENT, VIRTUAL_KEY, VK_CONTROL, VK_LWIN, VK_MENU,
+    VK_RWIN, VK_SHIFT,
 };
 use windows::Win32::UI::WindowsAndMessaging::{
-    self as msg, AdjustWindowRectEx, CreateWindowExW, DefWindowProcW, DestroyWindow, GetClientRect,
-    GetWindowLongPtrW, LoadCursorW, RegisterClassW, SetCursor, SetCursorPos, SetWindowLongPtrW,
+    self as msg, AdjustWindowRectEx, CreateWindowExW, DefWindowProcW, DestroyWindow, GetAncestor,
+    GetClientRect, GetMessageTime, GetSystemMetrics, GetWindowLongPtrW, IsIconic, IsWindowVisible,
+    LoadCursorW, RegisterClassW, SetCursor, SetCursorPos, SetWindowLongPtrW, SetWindowPos,
     ShowWindow, UnregisterClassW, CREATESTRUCTW, HCURSOR, HICON, HMENU, WINDOW_EX_STYLE, WNDCLASSW,
 };
 
 use super::event_loop::EventLoopState;
 use super::{class_name, hinstance, to_wstring};
 use crate::{
-    Bitmap, Cursor, Error, Event, EventLoop, MouseButton, Point, RawWindow, Rect, Response, Result,
-    Size, WindowOptions,
+    Bitmap, BitmapMut, Cursor, Error, Event, EventLoop, Modifiers, MouseButton, MouseEvent,
+    OwnedBitmap, PixelFormat, Point, RawWindow, Rect, Response, Result, Scaling, ScrollDelta,
+    ScrollEvent, Size, WindowOptions,
 };
 
 #[allow(non_snake_case)]
@@ -58,6 +62,29 @@ fn GET_WHEEL_DELTA_WPARAM(wParam: WPARAM) -> i16 {
 
 const WHEEL_DELTA: u16 = 120;
 
+fn get_modifiers() -> Modifiers {
+    fn is_down(key: VIRTUAL_KEY) -> bool {
+        unsafe { GetKeyState(key.0 as i32) < 0 }
+    }
+
+    let mut modifiers = Modifiers::empty();
+
+    if is_down(VK_SHIFT) {
+        modifiers |= Modifiers::SHIFT;
+    }
+    if is_down(VK_CONTROL) {
+        modifiers |= Modifiers::CONTROL;
+    }
+    if is_down(VK_MENU) {
+        modifiers |= Modifiers::ALT;
+    }
+    if is_down(VK_LWIN) || is_down(VK_RWIN) {
+        modifiers |= Modifiers::META;
+    }
+
+    modifiers
+}
+
 pub fn register_class() -> Result<PCWSTR> {
     let class_name = to_wstring(&class_name("window-"));
 
@@ -163,6 +190,8 @@ pub unsafe extern "system" fn wnd_proc(
                 return Some(LRESULT(0));
             }
             msg::WM_MOUSEMOVE => {
+                state.event_loop.state.timestamps.extend(GetMessageTime() as u32);
+
                 if !state.mouse_in_window.get() {
                     state.mouse_in_window.set(true);
                     state.handle_event(Event::MouseEnter);
@@ -189,6 +218,38 @@ pub unsafe extern "system" fn wnd_proc(
                 state.mouse_in_window.set(false);
                 state.handle_event(Event::MouseExit);
             }
+            msg::WM_SIZE => {
+                if wparam.0 as u32 != msg::SIZE_MINIMIZED {
+                    let size_physical = Size::new(
+                        LOWORD(lparam.0 as u32) as f64,
+                        HIWORD(lparam.0 as u32) as f64,
+                    );
+                    state.handle_event(Event::Resize(size_physical.scale(state.scale().recip())));
+                }
+
+                return Some(LRESULT(0));
+            }
+            msg::WM_GETMINMAXINFO => {
+                let min_max_info = &mut *(lparam.0 as *mut msg::MINMAXINFO);
+                let scale = state.scale();
+
+                if let Some(min_size) = state.min_size {
+                    min_max_info.ptMinTrackSize = state.outer_size(min_size.scale(scale));
+                }
+                if let Some(max_size) = state.max_size {
+                    min_max_info.ptMaxTrackSize = state.outer_size(max_size.scale(scale));
+                }
+
+                return Some(LRESULT(0));
+            }
+            msg::WM_SETFOCUS => {
+                state.handle_event(Event::GainFocus);
+                return Some(LRESULT(0));
+            }
+            msg::WM_KILLFOCUS => {
+                state.handle_event(Event::LoseFocus);
+                return Some(LRESULT(0));
+            }
             msg::WM_LBUTTONDOWN
             | msg::WM_LBUTTONUP
             | msg::WM_MBUTTONDOWN
@@ -210,16 +271,36 @@ pub unsafe extern "system" fn wnd_proc(
                 };
 
                 if let Some(button) = button {
-                    let event = match msg {
+                    let is_down = matches!(
+                        msg,
                         msg::WM_LBUTTONDOWN
-                        | msg::WM_MBUTTONDOWN
-                        | msg::WM_RBUTTONDOWN
-                        | msg::WM_XBUTTONDOWN => Some(Event::MouseDown(button)),
-                        msg::WM_LBUTTONUP
-                        | msg::WM_MBUTTONUP
-                        | msg::WM_RBUTTONUP
-                        | msg::WM_XBUTTONUP => Some(Event::MouseUp(button)),
-                        _ => None,
+                            | msg::WM_MBUTTONDOWN
+                            | msg::WM_RBUTTONDOWN
+                            | msg::WM_XBUTTONDOWN
+                    );
+
+                    let x = GET_X_LPARAM(lparam) as i32;
+                    let y = GET_Y_LPARAM(lparam) as i32;
+                    let time = GetMessageTime() as u32;
+                    let click_count = if is_down {
+                        state.click_count_for_press(button, time, x, y)
+                    } else {
+                        state.click_count_for_release(button)
+                    };
+
+                    let point_physical = Point::new(x as f64, y as f64);
+                    let mouse_event = MouseEvent {
+                        button,
+                        position: point_physical.scale(state.scale().recip()),
+                        modifiers: get_modifiers(),
+                        click_count,
+                        time: state.event_loop.state.timestamps.extend(time),
+                    };
+
+                    let event = if is_down {
+                        Some(Event::MouseDown(mouse_event))
+                    } else {
+                        Some(Event::MouseUp(mouse_event))
                     };
 
                     if let Some(event) = event {
@@ -247,13 +328,28 @@ pub unsafe extern "system" fn wnd_proc(
             }
             msg::WM_MOUSEWHEEL | msg::WM_MOUSEHWHEEL => {
                 let delta = GET_WHEEL_DELTA_WPARAM(wparam) as f64 / WHEEL_DELTA as f64;
-                let point = match msg {
+                let delta = match msg {
                     msg::WM_MOUSEWHEEL => Point::new(0.0, delta),
                     msg::WM_MOUSEHWHEEL => Point::new(delta, 0.0),
                     _ => unreachable!(),
                 };
 
-                if state.handle_event(Event::Scroll(point)) == Some(Response::Capture) {
+                // Wheel messages are in screen coordinates, unlike other mouse messages.
+                let mut point = POINT {
+                    x: GET_X_LPARAM(lparam) as c_int,
+                    y: GET_Y_LPARAM(lparam) as c_int,
+                };
+                gdi::ScreenToClient(hwnd, &mut point);
+                let point_physical = Point::new(point.x as f64, point.y as f64);
+
+                let scroll_event = ScrollEvent {
+                    delta: ScrollDelta::Lines(delta),
+                    phase: None,
+                    position: point_physical.scale(state.scale().recip()),
+                    modifiers: get_modifiers(),
+                    time: state.event_loop.state.timestamps.extend(GetMessageTime() as u32),
+                };
+                if state.handle_event(Event::Scroll(scroll_event)) == Some(Response::Capture) {
                     return Some(LRESULT(0));
                 }
             }
@@ -291,11 +387,27 @@ pub unsafe extern "system" fn wnd_proc(
     }
 }
 
+#[derive(Copy, Clone)]
+struct Click {
+    button: MouseButton,
+    time: u32,
+    x: i32,
+    y: i32,
+    count: u32,
+}
+
 pub struct WindowState {
     hwnd: Cell<Option<HWND>>,
     mouse_down_count: Cell<isize>,
+    last_click: Cell<Option<Click>>,
     mouse_in_window: Cell<bool>,
+    min_size: Option<Size>,
+    max_size: Option<Size>,
+    // Used by with_buffer, which draws into it and then presents it.
+    buffer: RefCell<Vec<u32>>,
     cursor: Cell<Cursor>,
+    frame_requested: Cell<bool>,
+    continuous_frames: Cell<bool>,
     event_loop: EventLoop,
     handler: RefCell<Box<dyn FnMut(Event) -> Response>>,
 }
@@ -309,6 +421,70 @@ impl WindowState {
         state
     }
 
+    // Converts a client area size to the size of the whole window, including its frame.
+    fn outer_size(&self, size_physical: Size) -> POINT {
+        let Some(hwnd) = self.hwnd.get() else {
+            return POINT { x: 0, y: 0 };
+        };
+
+        let mut rect = RECT {
+            left: 0,
+            top: 0,
+            right: size_physical.width.round() as i32,
+            bottom: size_physical.height.round() as i32,
+        };
+        unsafe {
+            let style = msg::WINDOW_STYLE(GetWindowLongPtrW(hwnd, msg::GWL_STYLE) as u32);
+            let _ = AdjustWindowRectEx(&mut rect, style, FALSE, WINDOW_EX_STYLE(0));
+        }
+
+        POINT {
+            x: rect.right - rect.left,
+            y: rect.bottom - rect.top,
+        }
+    }
+
+    fn click_count_for_press(&self, button: MouseButton, time: u32, x: i32, y: i32) -> u32 {
+        // SM_CXDOUBLECLK and SM_CYDOUBLECLK give the size of a rectangle centered on the first
+        // click which the second click must fall within.
+        let (max_dx, max_dy, max_time) = unsafe {
+            (
+                GetSystemMetrics(msg::SM_CXDOUBLECLK) / 2,
+                GetSystemMetrics(msg::SM_CYDOUBLECLK) / 2,
+                GetDoubleClickTime(),
+            )
+        };
+
+        let count = match self.last_click.get() {
+            Some(last)
+                if last.button == button
+                    && time.wrapping_sub(last.time) <= max_time
+                    && (x - last.x).abs() <= max_dx
+                    && (y - last.y).abs() <= max_dy =>
+            {
+                last.count + 1
+            }
+            _ => 1,
+        };
+
+        self.last_click.set(Some(Click {
+            button,
+            time,
+            x,
+            y,
+            count,
+        }));
+
+        count
+    }
+
+    fn click_count_for_release(&self, button: MouseButton) -> u32 {
+        match self.last_click.get() {
+            Some(last) if last.button == button => last.count,
+            _ => 1,
+        }
+    }
+
     fn update_cursor(&self) {
         unsafe {
             let hcursor = match self.cursor.get() {
@@ -352,11 +528,11 @@ impl WindowState {
             if options.parent.is_some() {
                 style |= msg::WS_CHILD;
             } else {
-                style |= msg::WS_CAPTION
-                    | msg::WS_SIZEBOX
-                    | msg::WS_SYSMENU
-                    | msg::WS_MINIMIZEBOX
-                    | msg::WS_MAXIMIZEBOX;
+                style |= msg::WS_CAPTION | msg::WS_SYSMENU | msg::WS_MINIMIZEBOX;
+
+                if options.resizable {
+                    style |= msg::WS_SIZEBOX | msg::WS_MAXIMIZEBOX;
+                }
             }
 
             let parent = if let Some(parent) = options.parent {
@@ -414,8 +590,14 @@ impl WindowState {
             let state = Rc::new(WindowState {
                 hwnd: Cell::new(Some(hwnd)),
                 mouse_down_count: Cell::new(0),
+                last_click: Cell::new(None),
                 mouse_in_window: Cell::new(false),
+                min_size: options.min_size,
+                max_size: options.max_size,
+                buffer: RefCell::new(Vec::new()),
                 cursor: Cell::new(Cursor::Arrow),
+                frame_requested: Cell::new(false),
+                continuous_frames: Cell::new(false),
                 event_loop: event_loop.clone(),
                 handler: RefCell::new(Box::new(handler)),
             });
@@ -463,6 +645,24 @@ impl WindowState {
         }
     }
 
+    pub fn set_size(&self, size: Size) {
+        if let Some(hwnd) = self.hwnd.get() {
+            let outer_size = self.outer_size(size.scale(self.scale()));
+
+            unsafe {
+                let _ = SetWindowPos(
+                    hwnd,
+                    HWND(0),
+                    0,
+                    0,
+                    outer_size.x,
+                    outer_size.y,
+                    msg::SWP_NOMOVE | msg::SWP_NOZORDER | msg::SWP_NOACTIVATE,
+                );
+            }
+        }
+    }
+
     pub fn scale(&self) -> f64 {
         if let Some(hwnd) = self.hwnd.get() {
             let dpi = unsafe { self.event_loop.state.dpi.dpi_for_window(hwnd) };
@@ -477,6 +677,153 @@ impl WindowState {
         self.present_inner(bitmap, None);
     }
 
+    pub fn with_buffer<F: FnOnce(&mut BitmapMut)>(&self, f: F) {
+        let size_physical = self.size().scale(self.scale());
+        let width = size_physical.width.round() as usize;
+        let height = size_physical.height.round() as usize;
+
+        let mut data = self.buffer.take();
+        data.resize(width * height, 0);
+        let mut bitmap = BitmapMut::new(&mut data, width, height);
+        f(&mut bitmap);
+        self.present(bitmap.as_bitmap());
+        self.buffer.replace(data);
+    }
+
+    pub fn capture(&self) -> Result<OwnedBitmap> {
+        let hwnd = self.hwnd.get().ok_or(Error::WindowClosed)?;
+
+        let mut rect = RECT {
+            left: 0,
+            top: 0,
+            right: 0,
+            bottom: 0,
+        };
+        unsafe {
+            GetClientRect(hwnd, &mut rect)?;
+        }
+        let width = (rect.right - rect.left).max(0) as usize;
+        let height = (rect.bottom - rect.top).max(0) as usize;
+
+        // GDI can't create empty bitmaps.
+        if width == 0 || height == 0 {
+            return Ok(OwnedBitmap::new(Vec::new(), width, height));
+        }
+
+        let mut data = vec![0u32; width * height];
+
+        unsafe {
+            let hdc = gdi::GetDC(hwnd);
+            if hdc == gdi::HDC(0) {
+                return Err(windows::core::Error::from_win32().into());
+            }
+
+            // Copy the window contents into a bitmap, which can only be read once it is no longer
+            // selected into a device context.
+            let mem_dc = gdi::CreateCompatibleDC(hdc);
+            let hbitmap = gdi::CreateCompatibleBitmap(hdc, width as i32, height as i32);
+            let old = gdi::SelectObject(mem_dc, hbitmap);
+            let result = gdi::BitBlt(
+                mem_dc,
+                0,
+                0,
+                width as i32,
+                height as i32,
+                hdc,
+                0,
+                0,
+                gdi::SRCCOPY,
+            );
+            gdi::SelectObject(mem_dc, old);
+
+            let mut bitmap_info = gdi::BITMAPINFO {
+                bmiHeader: gdi::BITMAPINFOHEADER {
+                    biSize: mem::size_of::<gdi::BITMAPINFOHEADER>() as u32,
+                    biWidth: width as i32,
+                    biHeight: -(height as i32),
+                    biPlanes: 1,
+                    biBitCount: 32,
+                    biCompression: gdi::BI_RGB.0,
+                    ..mem::zeroed()
+                },
+                ..mem::zeroed()
+            };
+            let lines = gdi::GetDIBits(
+                mem_dc,
+                hbitmap,
+                0,
+                height as u32,
+                Some(data.as_mut_ptr() as *mut c_void),
+                &mut bitmap_info,
+                gdi::DIB_RGB_COLORS,
+            );
+
+            gdi::DeleteObject(hbitmap);
+            gdi::DeleteDC(mem_dc);
+            gdi::ReleaseDC(hwnd, hdc);
+
+            result?;
+            if lines == 0 && height > 0 {
+                return Err(windows::core::Error::from_win32().into());
+            }
+        }
+
+        // GDI leaves the top byte undefined.
+        let (_, bytes, _) = unsafe { data.align_to::<u8>() };
+        let bitmap = Bitmap::from_bytes(
+            bytes,
+            width,
+            height,
+            width * mem::size_of::<u32>(),
+            PixelFormat::Xrgb,
+        );
+
+        Ok(OwnedBitmap::from_bitmap(bitmap, PixelFormat::Xrgb))
+    }
+
+    pub fn scroll_contents(&self, rect: Rect, delta: Point) {
+        if let Some(hwnd) = self.hwnd.get() {
+            let scale = self.scale();
+            let rect_physical = rect.scale(scale);
+            let x0 = rect_physical.x.round() as i32;
+            let y0 = rect_physical.y.round() as i32;
+            let x1 = (rect_physical.x + rect_physical.width).round() as i32;
+            let y1 = (rect_physical.y + rect_physical.height).round() as i32;
+            let dx = (delta.x * scale).round() as i32;
+            let dy = (delta.y * scale).round() as i32;
+
+            // The part of the rectangle which stays inside it after moving.
+            let width = x1 - x0 - dx.abs();
+            let height = y1 - y0 - dy.abs();
+            if width <= 0 || height <= 0 {
+                return;
+            }
+
+            let src_x = x0 + (-dx).max(0);
+            let src_y = y0 + (-dy).max(0);
+
+            unsafe {
+                let hdc = gdi::GetDC(hwnd);
+                if hdc != gdi::HDC(0) {
+                    let _ = gdi::BitBlt(
+                        hdc,
+                        src_x + dx,
+                        src_y + dy,
+                        width,
+                        height,
+                        hdc,
+                        src_x,
+                        src_y,
+                        gdi::SRCCOPY,
+                    );
+                    gdi::ReleaseDC(hwnd, hdc);
+                }
+            }
+        }
+    }
+
+    pub fn set_scaling(&self, _scaling: Scaling) {}
+
     pub fn present_partial(&self, bitmap: Bitmap, rects: &[Rect]) {
         self.present_inner(bitmap, Some(rects));
     }
@@ -537,6 +884,19 @@ impl WindowState {
                         dealloc(ptr as *mut u8, layout);
                     }
 
+                    // GDI expects tightly packed BGRX pixels.
+                    let converted;
+                    let data = match bitmap.packed(PixelFormat::Xrgb) {
+                        Some(data) => data,
+                        None => {
+                            let row_bytes = bitmap.width() * mem::size_of::<u32>();
+                            let mut buffer = vec![0; row_bytes * bitmap.height()];
+                            bitmap.copy_to(&mut buffer, row_bytes, PixelFormat::Xrgb);
+                            converted = buffer;
+                            &converted[..]
+                        }
+                    };
+
                     let bitmap_info = gdi::BITMAPINFO {
                         bmiHeader: gdi::BITMAPINFOHEADER {
                             biSize: mem::size_of::<gdi::BITMAPINFOHEADER>() as u32,
@@ -560,7 +920,7 @@ impl WindowState {
                         0,
                         0,
                         bitmap.height() as u32,
-                        bitmap.data().as_ptr() as *const c_void,
+                        data.as_ptr() as *const c_void,
                         &bitmap_info,
                         gdi::DIB_RGB_COLORS,
                     );
@@ -575,6 +935,31 @@ impl WindowState {
         }
     }
 
+    pub fn request_frame(&self) {
+        self.frame_requested.set(true);
+    }
+
+    pub fn set_continuous_frames(&self, continuous: bool) {
+        self.continuous_frames.set(continuous);
+    }
+
+    // Called on every vblank. Returns whether Event::Frame should be sent. A request made while
+    // the window is hidden or minimized is kept until it is visible again.
+    pub fn take_frame(&self) -> bool {
+        let Some(hwnd) = self.hwnd.get() else {
+            return false;
+        };
+
+        let visible = unsafe {
+            IsWindowVisible(hwnd).as_bool() && !IsIconic(GetAncestor(hwnd, msg::GA_ROOT)).as_bool()
+        };
+        if !visible {
+            return false;
+        }
+
+        self.frame_requested.take() || self.continuous_frames.get()
+    }
+
     pub fn set_cursor(&self, cursor: Cursor) {
         self.cursor.set(cursor);
         self.update_cursor();
@@ -595,6 +980,28 @@ impl WindowState {
         }
     }
 
+    pub fn focus(&self) {
+        if let Some(hwnd) = self.hwnd.get() {
+            unsafe {
+                SetFocus(hwnd);
+            }
+        }
+    }
+
+    pub fn has_focus(&self) -> bool {
+        if let Some(hwnd) = self.hwnd.get() {
+            unsafe { GetFocus() == hwnd }
+        } else {
+            false
+        }
+    }
+
+    pub fn lock_pointer(&self) {}
+
+    pub fn unlock_pointer(&self) {}
+
+    pub fn set_ime_cursor_area(&self, _rect: Rect) {}
+
     pub fn close(&self) {
         if let Some(hwnd) = self.hwnd.take() {
             self.event_loop.state.windows.borrow_mut().remove(&hwnd.0);
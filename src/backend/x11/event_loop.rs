@@ -1,21 +1,36 @@
 use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use x11rb::connection::{Connection, RequestConnection};
-use x11rb::protocol::present::{self, ConnectionExt as _};
-use x11rb::protocol::shm;
-use x11rb::protocol::xproto::{self, Button, ConnectionExt as _, Window as WindowId};
+use x11rb::protocol::present;
+use x11rb::protocol::render;
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xinput;
+use x11rb::protocol::xproto::{
+    self, ConnectionExt as _, EventMask, ImageFormat, ImageOrder, InputFocus, KeyButMask,
+    KeyPressEvent, NotifyDetail, NotifyMode, Setup, Timestamp, Visibility, VisualClass, Visualid,
+    Window as WindowId,
+};
 use x11rb::rust_connection::RustConnection;
 use x11rb::{cursor, protocol, resource_manager};
 
+use super::frame_clock::{self, FrameClock};
+use super::ime::{Ime, ImeEvent};
+use super::keyboard::Keyboard;
+use super::pointer::{fp1616_to_f64, Pointer};
 use super::timer::Timers;
 use super::window::WindowState;
-use crate::{Cursor, Error, Event, EventLoopOptions, MouseButton, Point, Rect, Response, Result};
+use super::xsettings::XSettings;
+use crate::timestamp::Timestamps;
+use crate::{
+    Cursor, Error, Event, EventLoopOptions, Modifiers, MouseButton, MouseEvent, PixelFormat, Point,
+    Rect, Response, Result, ScrollDelta, ScrollEvent, Size,
+};
 
-fn mouse_button_from_code(code: Button) -> Option<MouseButton> {
+fn mouse_button_from_code(code: u32) -> Option<MouseButton> {
     match code {
         1 => Some(MouseButton::Left),
         2 => Some(MouseButton::Middle),
@@ -26,7 +41,7 @@ fn mouse_button_from_code(code: Button) -> Option<MouseButton> {
     }
 }
 
-fn scroll_delta_from_code(code: Button) -> Option<Point> {
+fn scroll_delta_from_code(code: u32) -> Option<Point> {
     match code {
         4 => Some(Point::new(0.0, 1.0)),
         5 => Some(Point::new(0.0, -1.0)),
@@ -36,12 +51,140 @@ fn scroll_delta_from_code(code: Button) -> Option<Point> {
     }
 }
 
+fn event_timestamp(event: &protocol::Event) -> Option<Timestamp> {
+    match event {
+        protocol::Event::KeyPress(event) | protocol::Event::KeyRelease(event) => Some(event.time),
+        protocol::Event::ButtonPress(event) | protocol::Event::ButtonRelease(event) => {
+            Some(event.time)
+        }
+        protocol::Event::MotionNotify(event) => Some(event.time),
+        protocol::Event::EnterNotify(event) | protocol::Event::LeaveNotify(event) => {
+            Some(event.time)
+        }
+        protocol::Event::PropertyNotify(event) => Some(event.time),
+        protocol::Event::XinputMotion(event)
+        | protocol::Event::XinputButtonPress(event)
+        | protocol::Event::XinputButtonRelease(event) => Some(event.time),
+        protocol::Event::XinputRawMotion(event) => Some(event.time),
+        protocol::Event::XkbStateNotify(event) => Some(event.time),
+        _ => None,
+    }
+}
+
+fn modifiers_from_state(state: KeyButMask) -> Modifiers {
+    let mut modifiers = Modifiers::empty();
+
+    if state.contains(KeyButMask::SHIFT) {
+        modifiers |= Modifiers::SHIFT;
+    }
+    if state.contains(KeyButMask::CONTROL) {
+        modifiers |= Modifiers::CONTROL;
+    }
+    if state.contains(KeyButMask::MOD1) {
+        modifiers |= Modifiers::ALT;
+    }
+    if state.contains(KeyButMask::MOD4) {
+        modifiers |= Modifiers::META;
+    }
+
+    modifiers
+}
+
+// Whether a focus event reflects a change in which window receives keyboard input. Events caused
+// by keyboard grabs (e.g. a window manager's task switcher) are ignored, as are events sent to
+// ancestors of the focus window and to the window under the pointer.
+fn is_focus_change(mode: NotifyMode, detail: NotifyDetail) -> bool {
+    mode != NotifyMode::GRAB
+        && mode != NotifyMode::UNGRAB
+        && matches!(
+            detail,
+            NotifyDetail::ANCESTOR | NotifyDetail::INFERIOR | NotifyDetail::NONLINEAR
+        )
+}
+
+// The format the server expects for the pixels of images in a visual with the given red mask.
+fn image_pixel_format(setup: &Setup, red_mask: Option<u32>, alpha: bool) -> PixelFormat {
+    match (setup.image_byte_order, red_mask) {
+        (ImageOrder::LSB_FIRST, Some(0xFF)) => PixelFormat::Rgba8,
+        (ImageOrder::LSB_FIRST, _) if cfg!(target_endian = "big") => PixelFormat::Bgra8,
+        // Other layouts are either the native one or can't be described by a PixelFormat.
+        _ if alpha => PixelFormat::Argb,
+        _ => PixelFormat::Xrgb,
+    }
+}
+
+// Used when the desktop environment doesn't provide these through XSETTINGS. These match the
+// defaults used by GTK.
+const DEFAULT_DOUBLE_CLICK_TIME: u32 = 400;
+const DEFAULT_DOUBLE_CLICK_DISTANCE: i32 = 5;
+
+// Used when RandR can't tell the refresh rate.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_micros(16_667);
+
+// The parts of core and XInput 2 button events that we use.
+struct ButtonEvent {
+    window: WindowId,
+    root: WindowId,
+    detail: u32,
+    // Coordinates are in physical pixels.
+    x: f64,
+    y: f64,
+    root_x: f64,
+    root_y: f64,
+    state: KeyButMask,
+    time: Timestamp,
+}
+
+impl From<&xproto::ButtonPressEvent> for ButtonEvent {
+    fn from(event: &xproto::ButtonPressEvent) -> ButtonEvent {
+        ButtonEvent {
+            window: event.event,
+            root: event.root,
+            detail: event.detail as u32,
+            x: event.event_x as f64,
+            y: event.event_y as f64,
+            root_x: event.root_x as f64,
+            root_y: event.root_y as f64,
+            state: event.state,
+            time: event.time,
+        }
+    }
+}
+
+impl From<&xinput::ButtonPressEvent> for ButtonEvent {
+    fn from(event: &xinput::ButtonPressEvent) -> ButtonEvent {
+        ButtonEvent {
+            window: event.event,
+            root: event.root,
+            detail: event.detail,
+            x: fp1616_to_f64(event.event_x),
+            y: fp1616_to_f64(event.event_y),
+            root_x: fp1616_to_f64(event.root_x),
+            root_y: fp1616_to_f64(event.root_y),
+            state: KeyButMask::from(event.mods.effective as u16),
+            time: event.time,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Click {
+    window: WindowId,
+    button: MouseButton,
+    time: Timestamp,
+    x: f64,
+    y: f64,
+    count: u32,
+}
+
 x11rb::atom_manager! {
     pub Atoms: AtomsCookie {
         WM_PROTOCOLS,
         WM_DELETE_WINDOW,
+        WM_TAKE_FOCUS,
         _NET_WM_NAME,
         UTF8_STRING,
+        _XKB_RULES_NAMES,
     }
 }
 
@@ -76,16 +219,40 @@ impl<'a> Drop for RunGuard<'a> {
 
 pub struct EventLoopState {
     pub run_state: Cell<RunState>,
-    pub connection: RustConnection,
+    pub connection: Rc<RustConnection>,
     pub screen_index: usize,
     pub atoms: Atoms,
-    pub shm_supported: bool,
+    pub shm_supported: Cell<bool>,
+    pub shm_fd_supported: bool,
+    // Whether shared memory pixmaps can be created, which are needed for PresentPixmap.
+    pub shm_pixmaps_supported: bool,
+    // The pixel format of the root visual, used by windows which aren't transparent.
+    pub pixel_format: PixelFormat,
+    // A 32-bit visual used by transparent windows, if the server has one.
+    pub argb_visual: Option<Visualid>,
+    pub argb_pixel_format: PixelFormat,
     pub present_supported: bool,
-    pub resources: resource_manager::Database,
+    pub render_supported: bool,
+    pub refresh_interval: Duration,
+    // Drives frames when the server doesn't support Present.
+    pub frame_clock: Option<FrameClock>,
     pub cursor_handle: cursor::Handle,
     pub cursor_cache: RefCell<HashMap<Cursor, xproto::Cursor>>,
     pub scale: f64,
+    pub double_click_time: u32,
+    pub double_click_distance: i32,
+    last_click: Cell<Option<Click>>,
+    // The time of the most recent input event, used for requests that shouldn't use CurrentTime.
+    pub user_time: Cell<Timestamp>,
+    // Extends server timestamps to 64 bits. Fed from every event which carries a timestamp.
+    timestamps: Timestamps,
+    pub focused_window: Cell<Option<WindowId>>,
+    pub pointer: Pointer,
+    pub keyboard: Keyboard,
+    pub ime: Ime,
     pub windows: RefCell<HashMap<WindowId, Rc<WindowState>>>,
+    // Events read while a window was waiting for IdleNotify, to be handled by drain_events.
+    pub pending_events: RefCell<VecDeque<protocol::Event>>,
     pub timers: Timers,
 }
 
@@ -103,11 +270,71 @@ impl EventLoopState {
         let (connection, screen_index) = x11rb::connect(None)?;
         let atoms = Atoms::new(&connection)?.reply()?;
         let shm_supported = connection.extension_information(shm::X11_EXTENSION_NAME)?.is_some();
+        // Passing memory as a file descriptor was added in MIT-SHM 1.2.
+        let shm_version = if shm_supported {
+            Some(connection.shm_query_version()?.reply()?)
+        } else {
+            None
+        };
+        let shm_fd_supported = shm_version
+            .as_ref()
+            .is_some_and(|version| (version.major_version, version.minor_version) >= (1, 2));
+        let shm_pixmaps_supported = shm_version.as_ref().is_some_and(|version| {
+            version.shared_pixmaps && version.pixmap_format == u8::from(ImageFormat::Z_PIXMAP)
+        });
+        let setup = connection.setup();
+        let screen = &setup.roots[screen_index];
+        let root_red_mask = screen
+            .allowed_depths
+            .iter()
+            .flat_map(|depth| &depth.visuals)
+            .find(|visual| visual.visual_id == screen.root_visual)
+            .map(|visual| visual.red_mask);
+        let pixel_format = image_pixel_format(setup, root_red_mask, false);
+        let argb_visual = screen
+            .allowed_depths
+            .iter()
+            .filter(|depth| depth.depth == 32)
+            .flat_map(|depth| &depth.visuals)
+            .find(|visual| {
+                visual.class == VisualClass::TRUE_COLOR
+                    && (visual.red_mask, visual.green_mask, visual.blue_mask)
+                        == (0xFF0000, 0xFF00, 0xFF)
+            })
+            .map(|visual| visual.visual_id);
+        let argb_pixel_format = image_pixel_format(setup, Some(0xFF0000), true);
         let present_supported =
             connection.extension_information(present::X11_EXTENSION_NAME)?.is_some();
+        let render_supported =
+            connection.extension_information(render::X11_EXTENSION_NAME)?.is_some();
+        // RandR errors aren't fatal, since the refresh rate is only an estimate anyway.
+        let refresh_interval = frame_clock::refresh_interval(&connection, screen.root)
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+        let frame_clock = if present_supported {
+            None
+        } else {
+            Some(FrameClock::new(refresh_interval))
+        };
         let resources = resource_manager::new_from_default(&connection)?;
         let cursor_handle = cursor::Handle::new(&connection, screen_index, &resources)?.reply()?;
 
+        let xsettings = XSettings::new(&connection, screen_index)?;
+        let double_click_time = xsettings
+            .get_integer("Net/DoubleClickTime")
+            .and_then(|time| u32::try_from(time).ok())
+            .unwrap_or(DEFAULT_DOUBLE_CLICK_TIME);
+        let double_click_distance = xsettings
+            .get_integer("Net/DoubleClickDistance")
+            .unwrap_or(DEFAULT_DOUBLE_CLICK_DISTANCE);
+
+        let pointer = Pointer::new(&connection, screen_index)?;
+        let keyboard = Keyboard::new(&connection, screen_index, &atoms)?;
+
+        let connection = Rc::new(connection);
+        let ime = Ime::new(&connection, screen_index);
+
         let scale = if let Ok(Some(dpi)) = resources.get_value::<u32>("Xft.dpi", "") {
             dpi as f64 / 96.0
         } else {
@@ -118,14 +345,31 @@ impl EventLoopState {
             run_state: Cell::new(RunState::Stopped),
             connection,
             screen_index,
-            shm_supported,
+            shm_supported: Cell::new(shm_supported),
+            shm_fd_supported,
+            shm_pixmaps_supported,
+            pixel_format,
+            argb_visual,
+            argb_pixel_format,
             present_supported,
+            render_supported,
+            refresh_interval,
+            frame_clock,
             atoms,
-            resources,
             cursor_handle,
             cursor_cache: RefCell::new(HashMap::new()),
             scale,
+            double_click_time,
+            double_click_distance,
+            last_click: Cell::new(None),
+            user_time: Cell::new(x11rb::CURRENT_TIME),
+            timestamps: Timestamps::new(),
+            focused_window: Cell::new(None),
+            pointer,
+            keyboard,
+            ime,
             windows: RefCell::new(HashMap::new()),
+            pending_events: RefCell::new(VecDeque::new()),
             timers: Timers::new(),
         });
 
@@ -140,6 +384,7 @@ impl EventLoopState {
         loop {
             self.drain_events()?;
             self.timers.poll();
+            self.poll_frame_clock()?;
             self.drain_events()?;
 
             if self.run_state.get() == RunState::Exiting {
@@ -152,9 +397,16 @@ impl EventLoopState {
                 revents: 0,
             }];
 
-            let timeout = if let Some(next_time) = self.timers.next_time() {
+            let frame_time = self.frame_clock.as_ref().and_then(|clock| clock.next_time());
+            let next_time = match (self.timers.next_time(), frame_time) {
+                (Some(timer_time), Some(frame_time)) => Some(timer_time.min(frame_time)),
+                (timer_time, frame_time) => timer_time.or(frame_time),
+            };
+
+            let timeout = if let Some(next_time) = next_time {
+                // Rounding down would wake up before the deadline and spin until it passes.
                 let duration = next_time.saturating_duration_since(Instant::now());
-                duration.as_millis() as i32
+                duration.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
             } else {
                 -1
             };
@@ -178,11 +430,125 @@ impl EventLoopState {
 
         self.drain_events()?;
         self.timers.poll();
+        self.poll_frame_clock()?;
         self.drain_events()?;
 
         Ok(())
     }
 
+    fn handle_map_change(&self, window_id: WindowId, mapped: bool) -> Result<()> {
+        if let Some(window) = self.get_window(window_id) {
+            window.mapped.set(mapped);
+            // The server sends a new VisibilityNotify when the window is mapped again.
+            window.obscured.set(false);
+            self.update_visibility(&window)?;
+        }
+
+        // The window may be any ancestor of an embedded window, not just its parent.
+        let children: Vec<Rc<WindowState>> = self
+            .windows
+            .borrow()
+            .values()
+            .filter(|window| window.ancestors.borrow().contains(&window_id))
+            .cloned()
+            .collect();
+        for child in children {
+            child.update_ancestors_mapped()?;
+            self.update_visibility(&child)?;
+        }
+
+        Ok(())
+    }
+
+    // Moving an embedded window or one of its ancestors to a new parent changes which windows
+    // have to be watched.
+    fn handle_reparent(&self, window_id: WindowId) -> Result<()> {
+        let windows: Vec<Rc<WindowState>> = self
+            .windows
+            .borrow()
+            .values()
+            .filter(|window| {
+                window.window_id.get() == Some(window_id)
+                    || window.ancestors.borrow().contains(&window_id)
+            })
+            .cloned()
+            .collect();
+        for window in windows {
+            // Fails if one of the windows has been destroyed in the meantime.
+            let _ = window.watch_ancestors();
+            self.update_visibility(&window)?;
+        }
+        self.connection.flush()?;
+
+        Ok(())
+    }
+
+    fn update_visibility(&self, window: &WindowState) -> Result<()> {
+        if let Some(visible) = window.update_visibility() {
+            self.handle_event(window, Event::Visible(visible));
+
+            // Resume frames requested while the window was hidden.
+            window.schedule_frame()?;
+        }
+
+        Ok(())
+    }
+
+    fn poll_frame_clock(&self) -> Result<()> {
+        let Some((tick, time)) = self.frame_clock.as_ref().and_then(|clock| clock.poll()) else {
+            return Ok(());
+        };
+
+        let windows: Vec<Rc<WindowState>> = self.windows.borrow().values().cloned().collect();
+        for window in windows {
+            if let Some(frame_info) = window.take_frame(tick, time) {
+                self.handle_event(&window, Event::Frame(frame_info));
+            }
+
+            window.schedule_frame()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_cursor(&self, cursor: Cursor) -> Result<xproto::Cursor> {
+        if let Some(cursor_id) = self.cursor_cache.borrow().get(&cursor) {
+            return Ok(*cursor_id);
+        }
+
+        let connection = &self.connection;
+
+        let cursor_id = if cursor == Cursor::None {
+            let cursor_id = connection.generate_id()?;
+            let pixmap_id = connection.generate_id()?;
+            let root = connection.setup().roots[self.screen_index].root;
+            connection.create_pixmap(1, pixmap_id, root, 1, 1)?;
+            connection.create_cursor(cursor_id, pixmap_id, pixmap_id, 0, 0, 0, 0, 0, 0, 0, 0)?;
+            connection.free_pixmap(pixmap_id)?;
+
+            cursor_id
+        } else {
+            let cursor_name = match cursor {
+                Cursor::Arrow => "left_ptr",
+                Cursor::Crosshair => "crosshair",
+                Cursor::Hand => "hand2",
+                Cursor::IBeam => "text",
+                Cursor::No => "crossed_circle",
+                Cursor::SizeNs => "v_double_arrow",
+                Cursor::SizeWe => "h_double_arrow",
+                Cursor::SizeNesw => "fd_double_arrow",
+                Cursor::SizeNwse => "bd_double_arrow",
+                Cursor::Wait => "watch",
+                Cursor::None => unreachable!(),
+            };
+            self.cursor_handle.load_cursor(&**connection, cursor_name)?
+        };
+
+        self.cursor_cache.borrow_mut().insert(cursor, cursor_id);
+
+        Ok(cursor_id)
+    }
+
     fn get_window(&self, id: WindowId) -> Option<Rc<WindowState>> {
         self.windows.borrow().get(&id).cloned()
     }
@@ -192,93 +558,596 @@ impl EventLoopState {
         Some(handler(event))
     }
 
+    // Converts a server timestamp to a monotonic event time.
+    fn event_time(&self, time: Timestamp) -> Duration {
+        self.timestamps.extend(time)
+    }
+
+    fn click_count_for_press(
+        &self,
+        window: WindowId,
+        button: MouseButton,
+        time: Timestamp,
+        x: f64,
+        y: f64,
+    ) -> u32 {
+        let distance = self.double_click_distance as f64;
+
+        let count = match self.last_click.get() {
+            Some(last)
+                if last.window == window
+                    && last.button == button
+                    && time.wrapping_sub(last.time) <= self.double_click_time
+                    && (x - last.x).abs() <= distance
+                    && (y - last.y).abs() <= distance =>
+            {
+                last.count + 1
+            }
+            _ => 1,
+        };
+
+        self.last_click.set(Some(Click {
+            window,
+            button,
+            time,
+            x,
+            y,
+            count,
+        }));
+
+        count
+    }
+
+    fn click_count_for_release(&self, window: WindowId, button: MouseButton) -> u32 {
+        match self.last_click.get() {
+            Some(last) if last.window == window && last.button == button => last.count,
+            _ => 1,
+        }
+    }
+
+    // Crossing events are also generated when grabs start and end, so MouseEnter and MouseExit
+    // are only sent when the hovered window actually changes. While the pointer is captured,
+    // MouseExit is deferred until the capture ends.
+    fn handle_mouse_enter(&self, window_id: WindowId, x: f64, y: f64) -> Result<()> {
+        let Some(window) = self.get_window(window_id) else {
+            return Ok(());
+        };
+
+        self.pointer.reset_scroll_positions();
+
+        let entered = self.pointer.hovered_window() != Some(window_id);
+        self.pointer.set_hovered_window(Some(window_id));
+        if entered && self.pointer.captured_window().is_none() {
+            self.handle_event(&window, Event::MouseEnter);
+        }
+
+        self.handle_mouse_move(window_id, x, y)
+    }
+
+    fn handle_mouse_exit(&self, window_id: WindowId) {
+        let Some(window) = self.get_window(window_id) else {
+            return;
+        };
+
+        if self.pointer.hovered_window() != Some(window_id) {
+            return;
+        }
+
+        self.pointer.set_hovered_window(None);
+        if self.pointer.captured_window() != Some(window_id) {
+            self.handle_event(&window, Event::MouseExit);
+        }
+    }
+
+    // Coordinates are in physical pixels.
+    fn handle_mouse_move(&self, window_id: WindowId, x: f64, y: f64) -> Result<()> {
+        let Some(window) = self.get_window(window_id) else {
+            return Ok(());
+        };
+
+        // While the pointer is locked, only relative motion is reported.
+        if self.pointer.locked_window() == Some(window_id) {
+            if !self.pointer.xi2_supported() {
+                let delta = self.pointer.warp_motion_delta(&self.connection, x as i16, y as i16)?;
+                if let Some(delta) = delta {
+                    self.handle_event(&window, Event::RawMouseMotion(delta));
+                }
+            }
+
+            return Ok(());
+        }
+
+        let point = Point::new(x, y).scale(self.scale.recip());
+        self.handle_event(&window, Event::MouseMove(point));
+
+        Ok(())
+    }
+
+    fn handle_button_press(&self, event: &ButtonEvent) -> Result<()> {
+        let Some(window) = self.get_window(event.window) else {
+            return Ok(());
+        };
+
+        self.user_time.set(event.time);
+
+        let position = Point::new(event.x, event.y).scale(self.scale.recip());
+        let modifiers = modifiers_from_state(event.state);
+
+        if let Some(button) = mouse_button_from_code(event.detail) {
+            let click_count =
+                self.click_count_for_press(event.window, button, event.time, event.x, event.y);
+            let mouse_event = MouseEvent {
+                button,
+                position,
+                modifiers,
+                click_count,
+                time: self.event_time(event.time),
+            };
+            let response = self.handle_event(&window, Event::MouseDown(mouse_event));
+            if response == Some(Response::Capture) {
+                self.pointer.capture(&self.connection, event.window, event.detail, event.time)?;
+            }
+        } else if let Some(delta) = scroll_delta_from_code(event.detail) {
+            let response = self.handle_scroll(&window, delta, position, modifiers, event.time);
+            window.scroll_ignored.set(response == Some(Response::Ignore));
+            self.forward_scroll_button(&window, xproto::BUTTON_PRESS_EVENT, event)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_button_release(&self, event: &ButtonEvent) -> Result<()> {
+        let Some(window) = self.get_window(event.window) else {
+            return Ok(());
+        };
+
+        self.user_time.set(event.time);
+
+        if let Some(button) = mouse_button_from_code(event.detail) {
+            let mouse_event = MouseEvent {
+                button,
+                position: Point::new(event.x, event.y).scale(self.scale.recip()),
+                modifiers: modifiers_from_state(event.state),
+                click_count: self.click_count_for_release(event.window, button),
+                time: self.event_time(event.time),
+            };
+            self.handle_event(&window, Event::MouseUp(mouse_event));
+
+            if self.pointer.release_capture(&self.connection, event.window, event.detail)? {
+                self.connection.flush()?;
+
+                if self.pointer.hovered_window() != Some(event.window) {
+                    self.handle_event(&window, Event::MouseExit);
+                }
+            }
+        } else if scroll_delta_from_code(event.detail).is_some() {
+            self.forward_scroll_button(&window, xproto::BUTTON_RELEASE_EVENT, event)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_scroll(
+        &self,
+        window: &WindowState,
+        delta: Point,
+        position: Point,
+        modifiers: Modifiers,
+        time: Timestamp,
+    ) -> Option<Response> {
+        let scroll_event = ScrollEvent {
+            delta: ScrollDelta::Lines(delta),
+            phase: None,
+            position,
+            modifiers,
+            time: self.event_time(time),
+        };
+        self.handle_event(window, Event::Scroll(scroll_event))
+    }
+
+    fn handle_key_event(&self, event: &KeyPressEvent) -> Result<()> {
+        let Some(window) = self.get_window(event.event) else {
+            return Ok(());
+        };
+
+        self.user_time.set(event.time);
+
+        let forward = if event.response_type & 0x7F == xproto::KEY_PRESS_EVENT {
+            let key_event = self.keyboard.key_down(event.detail);
+            let mut response = self.handle_event(&window, Event::KeyDown(key_event));
+
+            if let Some(text) = self.keyboard.text(event.detail) {
+                let text_response = self.handle_event(&window, Event::TextInput(&text));
+                if response == Some(Response::Ignore) {
+                    response = text_response;
+                }
+            }
+
+            let ignored = response == Some(Response::Ignore);
+            let mut forwarded_keys = window.forwarded_keys.borrow_mut();
+            if ignored {
+                forwarded_keys.insert(event.detail);
+            } else {
+                forwarded_keys.remove(&event.detail);
+            }
+
+            ignored
+        } else {
+            let key_event = self.keyboard.key_up(event.detail);
+            self.handle_event(&window, Event::KeyUp(key_event));
+
+            // Releases follow their presses, so that the parent never sees one without the other.
+            window.forwarded_keys.borrow_mut().remove(&event.detail)
+        };
+
+        if forward {
+            self.forward_key_event(&window, event)?;
+        }
+
+        Ok(())
+    }
+
+    // Input events ignored by the handler of an embedded window are sent on to its parent, so
+    // that e.g. the host's keyboard shortcuts keep working while the window has focus. The event
+    // propagates up from the parent until it reaches a window which has selected it.
+    fn forward_key_event(&self, window: &WindowState, event: &KeyPressEvent) -> Result<()> {
+        let (Some(window_id), Some(parent_id)) = (window.window_id.get(), window.parent_id) else {
+            return Ok(());
+        };
+
+        let (x, y) = window.position.get();
+
+        let mask = if event.response_type & 0x7F == xproto::KEY_PRESS_EVENT {
+            EventMask::KEY_PRESS
+        } else {
+            EventMask::KEY_RELEASE
+        };
+        let forwarded = KeyPressEvent {
+            event: parent_id,
+            child: window_id,
+            event_x: event.event_x.wrapping_add(x),
+            event_y: event.event_y.wrapping_add(y),
+            ..*event
+        };
+        self.connection.send_event(true, parent_id, mask, forwarded)?;
+        self.connection.flush()?;
+
+        Ok(())
+    }
+
+    // Scroll button events are forwarded if the handler ignored the most recent scroll event.
+    // Releases and the buttons emulated for smooth scrolling don't produce scroll events of their
+    // own, so they follow the response to the scroll event before them.
+    fn forward_scroll_button(
+        &self,
+        window: &WindowState,
+        response_type: u8,
+        event: &ButtonEvent,
+    ) -> Result<()> {
+        if !window.scroll_ignored.get() {
+            return Ok(());
+        }
+
+        let (Some(window_id), Some(parent_id)) = (window.window_id.get(), window.parent_id) else {
+            return Ok(());
+        };
+
+        let (x, y) = window.position.get();
+
+        let mask = if response_type == xproto::BUTTON_PRESS_EVENT {
+            EventMask::BUTTON_PRESS
+        } else {
+            EventMask::BUTTON_RELEASE
+        };
+        let forwarded = xproto::ButtonPressEvent {
+            response_type,
+            detail: event.detail as u8,
+            sequence: 0,
+            time: event.time,
+            root: event.root,
+            event: parent_id,
+            child: window_id,
+            root_x: event.root_x as i16,
+            root_y: event.root_y as i16,
+            event_x: (event.x as i16).wrapping_add(x),
+            event_y: (event.y as i16).wrapping_add(y),
+            state: event.state,
+            same_screen: true,
+        };
+        self.connection.send_event(true, parent_id, mask, forwarded)?;
+        self.connection.flush()?;
+
+        Ok(())
+    }
+
+    fn handle_expose(&self, window: WindowId, rect_physical: Rect, count: u16) {
+        if let Some(window) = self.get_window(window) {
+            let rect = rect_physical.scale(self.scale.recip());
+
+            let expose_rects = &window.expose_rects;
+            expose_rects.borrow_mut().push(rect);
+
+            if count == 0 {
+                let rects = expose_rects.take();
+                let rects_physical: Vec<Rect> =
+                    rects.iter().map(|rect| rect.scale(self.scale)).collect();
+                if !window.repaint_retained(&rects_physical) {
+                    self.handle_event(&window, Event::Expose(&rects));
+                }
+            }
+        }
+    }
+
+    fn handle_focus_change(&self, window_id: WindowId, focused: bool) {
+        let Some(window) = self.get_window(window_id) else {
+            return;
+        };
+
+        if focused {
+            if self.focused_window.get() != Some(window_id) {
+                self.focused_window.set(Some(window_id));
+                self.handle_event(&window, Event::GainFocus);
+            }
+        } else if self.focused_window.get() == Some(window_id) {
+            self.focused_window.set(None);
+            self.handle_event(&window, Event::LoseFocus);
+        }
+    }
+
+    fn handle_ime_events(&self) -> Result<()> {
+        for event in self.ime.take_events() {
+            match event {
+                ImeEvent::Preedit(window, text, caret) => {
+                    if let Some(window) = self.get_window(window) {
+                        self.handle_event(&window, Event::ImePreedit(&text, caret));
+                    }
+                }
+                ImeEvent::Commit(window, text) => {
+                    if let Some(window) = self.get_window(window) {
+                        self.handle_event(&window, Event::ImeCommit(&text));
+                    }
+                }
+                ImeEvent::Key(event) => {
+                    self.handle_key_event(&event)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn drain_events(&self) -> Result<()> {
         loop {
             if self.run_state.get() == RunState::Exiting {
                 break;
             }
 
-            let Some(event) = self.connection.poll_for_event()? else {
-                break;
+            let pending = self.pending_events.borrow_mut().pop_front();
+            let event = match pending {
+                Some(event) => event,
+                None => match self.connection.poll_for_event()? {
+                    Some(event) => event,
+                    None => break,
+                },
             };
 
+            if let Some(time) = event_timestamp(&event) {
+                self.timestamps.extend(time);
+            }
+
+            if self.ime.filter_event(&event) {
+                self.handle_ime_events()?;
+                continue;
+            }
+
             match event {
                 protocol::Event::Expose(event) => {
+                    let rect_physical = Rect {
+                        x: event.x as f64,
+                        y: event.y as f64,
+                        width: event.width as f64,
+                        height: event.height as f64,
+                    };
+                    self.handle_expose(event.window, rect_physical, event.count);
+                }
+                // Sent when Window::scroll_contents copies from an obscured part of the window,
+                // which then has to be redrawn.
+                protocol::Event::GraphicsExposure(event) => {
+                    let rect_physical = Rect {
+                        x: event.x as f64,
+                        y: event.y as f64,
+                        width: event.width as f64,
+                        height: event.height as f64,
+                    };
+                    self.handle_expose(event.drawable, rect_physical, event.count);
+                }
+                protocol::Event::ClientMessage(event)
+                    if event.format == 32
+                        && event.data.as_data32()[0] == self.atoms.WM_DELETE_WINDOW =>
+                {
                     if let Some(window) = self.get_window(event.window) {
-                        let rect_physical = Rect {
-                            x: event.x as f64,
-                            y: event.y as f64,
-                            width: event.width as f64,
-                            height: event.height as f64,
-                        };
-                        let rect = rect_physical.scale(self.scale.recip());
-
-                        let expose_rects = &window.expose_rects;
-                        expose_rects.borrow_mut().push(rect);
-
-                        if event.count == 0 {
-                            let rects = expose_rects.take();
-                            self.handle_event(&window, Event::Expose(&rects));
+                        self.handle_event(&window, Event::Close);
+                    }
+                }
+                protocol::Event::ConfigureNotify(event) => {
+                    if let Some(window) = self.get_window(event.window) {
+                        // Window managers send synthetic events with root coordinates.
+                        if event.response_type & 0x80 == 0 {
+                            window.position.set((event.x, event.y));
+                        }
+
+                        let size_physical = Size::new(event.width as f64, event.height as f64);
+                        if window.size.get() != size_physical {
+                            window.size.set(size_physical);
+
+                            let size = size_physical.scale(self.scale.recip());
+                            self.handle_event(&window, Event::Resize(size));
                         }
                     }
                 }
-                protocol::Event::ClientMessage(event) => {
+                // With the WM_TAKE_FOCUS protocol, the window manager asks us to set the focus
+                // ourselves instead of doing it directly.
+                protocol::Event::ClientMessage(event)
                     if event.format == 32
-                        && event.data.as_data32()[0] == self.atoms.WM_DELETE_WINDOW
-                    {
-                        if let Some(window) = self.get_window(event.window) {
-                            self.handle_event(&window, Event::Close);
-                        }
+                        && event.data.as_data32()[0] == self.atoms.WM_TAKE_FOCUS
+                        && self.get_window(event.window).is_some() =>
+                {
+                    let time = event.data.as_data32()[1];
+                    self.connection.set_input_focus(InputFocus::PARENT, event.window, time)?;
+                    self.connection.flush()?;
+                }
+                protocol::Event::MapNotify(event) => {
+                    self.handle_map_change(event.window, true)?;
+                }
+                protocol::Event::UnmapNotify(event) => {
+                    self.handle_map_change(event.window, false)?;
+                }
+                protocol::Event::ReparentNotify(event) => {
+                    if let Some(window) = self.get_window(event.window) {
+                        window.position.set((event.x, event.y));
                     }
+                    self.handle_reparent(event.window)?;
                 }
-                protocol::Event::EnterNotify(event) => {
-                    if let Some(window) = self.get_window(event.event) {
-                        self.handle_event(&window, Event::MouseEnter);
-
-                        let point = Point {
-                            x: event.event_x as f64,
-                            y: event.event_y as f64,
-                        };
-                        self.handle_event(&window, Event::MouseMove(point));
+                protocol::Event::VisibilityNotify(event) => {
+                    if let Some(window) = self.get_window(event.window) {
+                        window.obscured.set(event.state == Visibility::FULLY_OBSCURED);
+                        self.update_visibility(&window)?;
                     }
                 }
+                protocol::Event::EnterNotify(event) => {
+                    self.handle_mouse_enter(
+                        event.event,
+                        event.event_x as f64,
+                        event.event_y as f64,
+                    )?;
+                }
                 protocol::Event::LeaveNotify(event) => {
-                    if let Some(window) = self.get_window(event.event) {
-                        self.handle_event(&window, Event::MouseExit);
-                    }
+                    self.handle_mouse_exit(event.event);
                 }
                 protocol::Event::MotionNotify(event) => {
-                    if let Some(window) = self.get_window(event.event) {
-                        let point = Point {
-                            x: event.event_x as f64,
-                            y: event.event_y as f64,
-                        };
+                    self.handle_mouse_move(
+                        event.event,
+                        event.event_x as f64,
+                        event.event_y as f64,
+                    )?;
+                }
+                protocol::Event::ButtonPress(event) => {
+                    self.handle_button_press(&ButtonEvent::from(&event))?;
+                }
+                protocol::Event::ButtonRelease(event) => {
+                    self.handle_button_release(&ButtonEvent::from(&event))?;
+                }
+                protocol::Event::XinputMotion(event) => {
+                    let x = fp1616_to_f64(event.event_x);
+                    let y = fp1616_to_f64(event.event_y);
 
-                        self.handle_event(&window, Event::MouseMove(point));
+                    if let Some(delta) = self.pointer.scroll_delta(&event) {
+                        if let Some(window) = self.get_window(event.event) {
+                            let position = Point::new(x, y).scale(self.scale.recip());
+                            let state = KeyButMask::from(event.mods.effective as u16);
+                            let modifiers = modifiers_from_state(state);
+                            let response =
+                                self.handle_scroll(&window, delta, position, modifiers, event.time);
+                            window.scroll_ignored.set(response == Some(Response::Ignore));
+                        }
+                    }
+
+                    // A single event can both scroll and move the pointer.
+                    if self.pointer.has_motion(&event) {
+                        self.handle_mouse_move(event.event, x, y)?;
                     }
                 }
-                protocol::Event::ButtonPress(event) => {
-                    if let Some(window) = self.get_window(event.event) {
-                        if let Some(button) = mouse_button_from_code(event.detail) {
-                            self.handle_event(&window, Event::MouseDown(button));
-                        } else if let Some(delta) = scroll_delta_from_code(event.detail) {
-                            self.handle_event(&window, Event::Scroll(delta));
+                // Scroll wheel buttons emulated from smooth scrolling are only forwarded, since
+                // the smooth scroll deltas are reported via motion events.
+                protocol::Event::XinputButtonPress(event) => {
+                    let button_event = ButtonEvent::from(&event);
+                    if event.flags.contains(xinput::PointerEventFlags::POINTER_EMULATED) {
+                        if let Some(window) = self.get_window(event.event) {
+                            let response_type = xproto::BUTTON_PRESS_EVENT;
+                            self.forward_scroll_button(&window, response_type, &button_event)?;
                         }
+                    } else {
+                        self.handle_button_press(&button_event)?;
                     }
                 }
-                protocol::Event::ButtonRelease(event) => {
-                    if let Some(window) = self.get_window(event.event) {
-                        if let Some(button) = mouse_button_from_code(event.detail) {
-                            self.handle_event(&window, Event::MouseUp(button));
+                protocol::Event::XinputButtonRelease(event) => {
+                    let button_event = ButtonEvent::from(&event);
+                    if event.flags.contains(xinput::PointerEventFlags::POINTER_EMULATED) {
+                        if let Some(window) = self.get_window(event.event) {
+                            let response_type = xproto::BUTTON_RELEASE_EVENT;
+                            self.forward_scroll_button(&window, response_type, &button_event)?;
+                        }
+                    } else {
+                        self.handle_button_release(&button_event)?;
+                    }
+                }
+                protocol::Event::XinputRawMotion(event) => {
+                    if let Some(window_id) = self.pointer.locked_window() {
+                        if let Some(window) = self.get_window(window_id) {
+                            let delta = self.pointer.raw_motion_delta(&event);
+                            self.handle_event(&window, Event::RawMouseMotion(delta));
                         }
                     }
                 }
-                protocol::Event::PresentCompleteNotify(event) => {
+                protocol::Event::XinputHierarchy(_) | protocol::Event::XinputDeviceChanged(_) => {
+                    self.pointer.update_devices(&self.connection)?;
+                }
+                protocol::Event::KeyPress(event) | protocol::Event::KeyRelease(event) => {
+                    // Key events are routed through the input method if there is one, and
+                    // arrive back in handle_ime_events if it doesn't consume them.
+                    if !self.ime.forward_key(&event) {
+                        self.handle_key_event(&event)?;
+                    }
+                    self.connection.flush()?;
+                }
+                protocol::Event::FocusIn(event) if is_focus_change(event.mode, event.detail) => {
+                    self.ime.set_focus(event.event, true);
+                    self.connection.flush()?;
+
+                    self.handle_focus_change(event.event, true);
+                }
+                protocol::Event::FocusOut(event) if is_focus_change(event.mode, event.detail) => {
+                    self.ime.set_focus(event.event, false);
+                    self.keyboard.reset_compose();
+                    self.keyboard.reset_pressed();
+                    self.connection.flush()?;
+
+                    self.handle_focus_change(event.event, false);
+                }
+                protocol::Event::XkbStateNotify(event) => {
+                    self.keyboard.update_state(&event);
+                }
+                protocol::Event::XkbNewKeyboardNotify(_) | protocol::Event::XkbMapNotify(_) => {
+                    self.keyboard.update_keymap(
+                        &self.connection,
+                        self.screen_index,
+                        &self.atoms,
+                    )?;
+                }
+                // NotifyMSC completions drive frames. Another notification is only requested if
+                // the window still wants frames after handling this one.
+                protocol::Event::PresentCompleteNotify(event)
+                    if event.kind == present::CompleteKind::NOTIFY_MSC =>
+                {
                     if let Some(window) = self.get_window(event.window) {
-                        self.handle_event(&window, Event::Frame);
+                        if let Some(frame_info) = window.take_frame(event.msc, event.ust) {
+                            self.handle_event(&window, Event::Frame(frame_info));
+                        }
 
-                        self.connection.present_notify_msc(event.window, 0, 0, 1, 0)?;
-                        self.connection.flush()?;
+                        window.schedule_frame()?;
+                    }
+                }
+                // Completions of PresentPixmap requests say when the bitmap reached the screen.
+                protocol::Event::PresentCompleteNotify(event)
+                    if event.mode != present::CompleteMode::SKIP =>
+                {
+                    if let Some(window) = self.get_window(event.window) {
+                        window.handle_pixmap_complete(event.ust);
+                    }
+                }
+                protocol::Event::PresentIdleNotify(event) => {
+                    if let Some(window) = self.get_window(event.window) {
+                        window.handle_pixmap_idle(event.pixmap);
                     }
                 }
                 _ => {}
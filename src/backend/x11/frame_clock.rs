@@ -0,0 +1,115 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use x11rb::connection::RequestConnection;
+use x11rb::protocol::randr::{self, ConnectionExt as _, ModeFlag};
+use x11rb::protocol::xproto::Window as WindowId;
+use x11rb::rust_connection::RustConnection;
+
+use crate::Result;
+
+// The refresh interval of the primary monitor according to RandR, or of the first active one if
+// there is no primary monitor.
+pub fn refresh_interval(connection: &RustConnection, root: WindowId) -> Result<Option<Duration>> {
+    if connection.extension_information(randr::X11_EXTENSION_NAME)?.is_none() {
+        return Ok(None);
+    }
+
+    // GetScreenResourcesCurrent and GetOutputPrimary require RandR 1.3.
+    let version = connection.randr_query_version(1, 3)?.reply()?;
+    if (version.major_version, version.minor_version) < (1, 3) {
+        return Ok(None);
+    }
+
+    let resources = connection.randr_get_screen_resources_current(root)?.reply()?;
+    let timestamp = resources.config_timestamp;
+
+    let primary = connection.randr_get_output_primary(root)?.reply()?.output;
+    let primary_crtc = if primary != x11rb::NONE {
+        connection.randr_get_output_info(primary, timestamp)?.reply()?.crtc
+    } else {
+        x11rb::NONE
+    };
+
+    let crtcs = (primary_crtc != x11rb::NONE)
+        .then_some(primary_crtc)
+        .into_iter()
+        .chain(resources.crtcs.iter().copied());
+    for crtc in crtcs {
+        let mode = connection.randr_get_crtc_info(crtc, timestamp)?.reply()?.mode;
+        if mode == x11rb::NONE {
+            continue;
+        }
+
+        let Some(mode_info) = resources.modes.iter().find(|mode_info| mode_info.id == mode) else {
+            continue;
+        };
+
+        let mut vtotal = mode_info.vtotal as f64;
+        if mode_info.mode_flags.contains(ModeFlag::DOUBLE_SCAN) {
+            vtotal *= 2.0;
+        }
+        if mode_info.mode_flags.contains(ModeFlag::INTERLACE) {
+            vtotal /= 2.0;
+        }
+
+        let pixels = mode_info.htotal as f64 * vtotal;
+        if pixels > 0.0 && mode_info.dot_clock > 0 {
+            return Ok(Some(Duration::from_secs_f64(
+                pixels / mode_info.dot_clock as f64,
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+// Generates frames on a timer when the Present extension isn't available. Ticks are numbered from
+// the creation of the clock, and the clock only wakes the event loop while a window has asked for
+// a frame.
+pub struct FrameClock {
+    start: Instant,
+    interval: Duration,
+    next_tick: Cell<Option<Instant>>,
+}
+
+impl FrameClock {
+    pub fn new(interval: Duration) -> FrameClock {
+        FrameClock {
+            start: Instant::now(),
+            interval,
+            next_tick: Cell::new(None),
+        }
+    }
+
+    pub fn schedule(&self) {
+        if self.next_tick.get().is_none() {
+            let elapsed = Instant::now().duration_since(self.start);
+            let tick = elapsed.as_nanos() / self.interval.as_nanos() + 1;
+            self.next_tick.set(Some(self.tick_time(tick)));
+        }
+    }
+
+    pub fn next_time(&self) -> Option<Instant> {
+        self.next_tick.get()
+    }
+
+    // Returns the number of the most recent tick and its time in microseconds since the start of
+    // the clock, if a scheduled tick has passed. Missed ticks are skipped.
+    pub fn poll(&self) -> Option<(u64, u64)> {
+        let now = Instant::now();
+        match self.next_tick.get() {
+            Some(next_tick) if next_tick <= now => self.next_tick.set(None),
+            _ => return None,
+        }
+
+        let tick = now.duration_since(self.start).as_nanos() / self.interval.as_nanos();
+        let time = self.tick_time(tick).duration_since(self.start);
+
+        Some((tick as u64, time.as_micros() as u64))
+    }
+
+    fn tick_time(&self, tick: u128) -> Instant {
+        self.start + Duration::from_nanos((tick * self.interval.as_nanos()) as u64)
+    }
+}
@@ -0,0 +1,372 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use x11rb::protocol::xproto::{self, EventMask, KeyPressEvent, Window as WindowId};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use xim::x11rb::X11rbClient;
+use xim::{
+    AHashMap, AttributeName, Client, ClientError, ClientHandler, Feedback, ForwardEventFlag,
+    InputStyle, InputStyleList, Point, PreeditDrawStatus,
+};
+
+use super::keyboard;
+
+type ImeClient = X11rbClient<Rc<RustConnection>>;
+
+// The locale name passed to the input method server, e.g. "en_US" for "en_US.UTF-8".
+fn im_locale() -> String {
+    let locale = keyboard::locale();
+    let name = locale.split(['.', '@']).next().unwrap_or_default();
+    if name.is_empty() || name == "C" || name == "POSIX" {
+        "en_US".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+pub enum ImeEvent {
+    Preedit(WindowId, String, Option<usize>),
+    Commit(WindowId, String),
+    Key(KeyPressEvent),
+}
+
+struct Context {
+    window: WindowId,
+    preedit: Vec<char>,
+}
+
+struct Handler {
+    im_id: Option<u16>,
+    style: InputStyle,
+    // Windows which should have an input context, including ones whose creation is pending.
+    windows: Vec<WindowId>,
+    // The server replies to CreateIc requests in order, without saying which window they were for.
+    pending: VecDeque<WindowId>,
+    contexts: HashMap<u16, Context>,
+    // The key events the server wants to see, as set with SetEventMask.
+    forward_mask: u32,
+    events: Vec<ImeEvent>,
+}
+
+impl Handler {
+    fn context_for_window(&self, window: WindowId) -> Option<u16> {
+        self.contexts
+            .iter()
+            .find(|(_, context)| context.window == window)
+            .map(|(id, _)| *id)
+    }
+
+    fn create_ic(&mut self, client: &mut ImeClient, window: WindowId) -> Result<(), ClientError> {
+        let Some(im_id) = self.im_id else {
+            return Ok(());
+        };
+
+        let attributes = client
+            .build_ic_attributes()
+            .push(AttributeName::InputStyle, self.style)
+            .push(AttributeName::ClientWindow, window)
+            .push(AttributeName::FocusWindow, window)
+            .build();
+        client.create_ic(im_id, attributes)?;
+
+        self.pending.push_back(window);
+
+        Ok(())
+    }
+
+    fn preedit_event(&mut self, ic_id: u16, caret: Option<usize>) {
+        if let Some(context) = self.contexts.get(&ic_id) {
+            let text: String = context.preedit.iter().collect();
+            let caret =
+                caret.map(|caret| context.preedit.iter().take(caret).map(|c| c.len_utf8()).sum());
+            self.events.push(ImeEvent::Preedit(context.window, text, caret));
+        }
+    }
+}
+
+impl ClientHandler<ImeClient> for Handler {
+    fn handle_connect(&mut self, client: &mut ImeClient) -> Result<(), ClientError> {
+        client.open(&im_locale())
+    }
+
+    fn handle_open(&mut self, client: &mut ImeClient, im_id: u16) -> Result<(), ClientError> {
+        self.im_id = Some(im_id);
+        client.get_im_values(im_id, &[AttributeName::QueryInputStyle])
+    }
+
+    fn handle_get_im_values(
+        &mut self,
+        client: &mut ImeClient,
+        _im_id: u16,
+        mut attributes: AHashMap<AttributeName, Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        let styles = attributes
+            .remove(&AttributeName::QueryInputStyle)
+            .and_then(|value| xim::read::<InputStyleList>(&value).ok())
+            .map(|list| list.styles)
+            .unwrap_or_default();
+
+        // Prefer drawing the preedit text ourselves, but fall back to letting the server draw it
+        // at the location given by set_ime_cursor_area.
+        let preferred = [
+            InputStyle::PREEDIT_CALLBACKS | InputStyle::STATUS_NOTHING,
+            InputStyle::PREEDIT_POSITION | InputStyle::STATUS_NOTHING,
+            InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING,
+        ];
+        self.style = preferred
+            .into_iter()
+            .find(|style| styles.contains(style))
+            .unwrap_or(InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING);
+
+        for window in self.windows.clone() {
+            self.create_ic(client, window)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_create_ic(
+        &mut self,
+        client: &mut ImeClient,
+        im_id: u16,
+        ic_id: u16,
+    ) -> Result<(), ClientError> {
+        let Some(window) = self.pending.pop_front() else {
+            return Ok(());
+        };
+
+        if !self.windows.contains(&window) {
+            return client.destroy_ic(im_id, ic_id);
+        }
+
+        self.contexts.insert(
+            ic_id,
+            Context {
+                window,
+                preedit: Vec::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn handle_commit(
+        &mut self,
+        _client: &mut ImeClient,
+        _im_id: u16,
+        ic_id: u16,
+        text: &str,
+    ) -> Result<(), ClientError> {
+        if let Some(context) = self.contexts.get(&ic_id) {
+            self.events.push(ImeEvent::Commit(context.window, text.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn handle_forward_event(
+        &mut self,
+        _client: &mut ImeClient,
+        _im_id: u16,
+        _ic_id: u16,
+        _flag: ForwardEventFlag,
+        event: KeyPressEvent,
+    ) -> Result<(), ClientError> {
+        self.events.push(ImeEvent::Key(event));
+
+        Ok(())
+    }
+
+    fn handle_set_event_mask(
+        &mut self,
+        _client: &mut ImeClient,
+        _im_id: u16,
+        _ic_id: u16,
+        forward_event_mask: u32,
+        _synchronous_event_mask: u32,
+    ) -> Result<(), ClientError> {
+        self.forward_mask = forward_event_mask;
+
+        Ok(())
+    }
+
+    fn handle_preedit_draw(
+        &mut self,
+        _client: &mut ImeClient,
+        _im_id: u16,
+        ic_id: u16,
+        caret: i32,
+        chg_first: i32,
+        chg_len: i32,
+        status: PreeditDrawStatus,
+        preedit_string: &str,
+        _feedbacks: Vec<Feedback>,
+    ) -> Result<(), ClientError> {
+        if let Some(context) = self.contexts.get_mut(&ic_id) {
+            let len = context.preedit.len();
+            let start = (chg_first.max(0) as usize).min(len);
+            let end = (start + chg_len.max(0) as usize).min(len);
+
+            let text = if status.contains(PreeditDrawStatus::NO_STRING) {
+                ""
+            } else {
+                preedit_string
+            };
+            context.preedit.splice(start..end, text.chars());
+
+            let caret = (caret.max(0) as usize).min(context.preedit.len());
+            self.preedit_event(ic_id, Some(caret));
+        }
+
+        Ok(())
+    }
+
+    fn handle_preedit_done(
+        &mut self,
+        _client: &mut ImeClient,
+        _im_id: u16,
+        ic_id: u16,
+    ) -> Result<(), ClientError> {
+        if let Some(context) = self.contexts.get_mut(&ic_id) {
+            context.preedit.clear();
+            self.preedit_event(ic_id, None);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Ime {
+    client: RefCell<Option<ImeClient>>,
+    handler: RefCell<Handler>,
+}
+
+impl Ime {
+    pub fn new(connection: &Rc<RustConnection>, screen_index: usize) -> Ime {
+        // Not having an input method server is the common case, and key events are then
+        // translated locally.
+        let client = X11rbClient::init(Rc::clone(connection), screen_index, None).ok();
+
+        Ime {
+            client: RefCell::new(client),
+            handler: RefCell::new(Handler {
+                im_id: None,
+                style: InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING,
+                windows: Vec::new(),
+                pending: VecDeque::new(),
+                contexts: HashMap::new(),
+                forward_mask: u32::from(EventMask::KEY_PRESS | EventMask::KEY_RELEASE),
+                events: Vec::new(),
+            }),
+        }
+    }
+
+    // Runs f with the client, disabling the input method entirely if the connection to the
+    // server fails.
+    fn with_client<F>(&self, f: F)
+    where
+        F: FnOnce(&mut ImeClient, &mut Handler) -> Result<(), ClientError>,
+    {
+        let mut client = self.client.borrow_mut();
+        let mut handler = self.handler.borrow_mut();
+
+        if let Some(client_ref) = client.as_mut() {
+            if f(client_ref, &mut handler).is_err() {
+                *client = None;
+                handler.im_id = None;
+                handler.pending.clear();
+                handler.contexts.clear();
+            }
+        }
+    }
+
+    pub fn filter_event(&self, event: &Event) -> bool {
+        let mut filtered = false;
+        self.with_client(|client, handler| {
+            filtered = client.filter_event(event, handler)?;
+            Ok(())
+        });
+
+        filtered
+    }
+
+    pub fn take_events(&self) -> Vec<ImeEvent> {
+        std::mem::take(&mut self.handler.borrow_mut().events)
+    }
+
+    pub fn create_context(&self, window: WindowId) {
+        self.handler.borrow_mut().windows.push(window);
+        self.with_client(|client, handler| handler.create_ic(client, window));
+    }
+
+    pub fn destroy_context(&self, window: WindowId) {
+        self.handler.borrow_mut().windows.retain(|w| *w != window);
+        self.with_client(|client, handler| {
+            if let (Some(im_id), Some(ic_id)) = (handler.im_id, handler.context_for_window(window))
+            {
+                handler.contexts.remove(&ic_id);
+                client.destroy_ic(im_id, ic_id)?;
+            }
+            Ok(())
+        });
+    }
+
+    // Sends a key event to the input method server, which will either consume it or forward it
+    // back to us. Returns false if the event should be handled locally instead.
+    pub fn forward_key(&self, event: &KeyPressEvent) -> bool {
+        let mask = if event.response_type & 0x7F == xproto::KEY_PRESS_EVENT {
+            EventMask::KEY_PRESS
+        } else {
+            EventMask::KEY_RELEASE
+        };
+
+        let mut forwarded = false;
+        self.with_client(|client, handler| {
+            if handler.forward_mask & u32::from(mask) == 0 {
+                return Ok(());
+            }
+
+            if let (Some(im_id), Some(ic_id)) =
+                (handler.im_id, handler.context_for_window(event.event))
+            {
+                client.forward_event(im_id, ic_id, ForwardEventFlag::empty(), event)?;
+                forwarded = true;
+            }
+            Ok(())
+        });
+
+        forwarded
+    }
+
+    pub fn set_focus(&self, window: WindowId, focused: bool) {
+        self.with_client(|client, handler| {
+            if let (Some(im_id), Some(ic_id)) = (handler.im_id, handler.context_for_window(window))
+            {
+                if focused {
+                    client.set_focus(im_id, ic_id)?;
+                } else {
+                    client.unset_focus(im_id, ic_id)?;
+                }
+            }
+            Ok(())
+        });
+    }
+
+    pub fn set_cursor_position(&self, window: WindowId, x: i16, y: i16) {
+        self.with_client(|client, handler| {
+            if let (Some(im_id), Some(ic_id)) = (handler.im_id, handler.context_for_window(window))
+            {
+                let attributes = client
+                    .build_ic_attributes()
+                    .nested_list(AttributeName::PreeditAttributes, |list| {
+                        list.push(AttributeName::SpotLocation, Point { x, y });
+                    })
+                    .build();
+                client.set_ic_values(im_id, ic_id, attributes)?;
+            }
+            Ok(())
+        });
+    }
+}
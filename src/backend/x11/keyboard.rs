@@ -0,0 +1,663 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::{c_char, c_int, CString};
+use std::ptr;
+
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::xkb::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _, Keycode};
+use x11rb::rust_connection::RustConnection;
+use x11rb::xcb_ffi::XCBConnection;
+use xkbcommon_dl::x11::{xkb_x11_setup_xkb_extension_flags, XkbCommonX11};
+use xkbcommon_dl::{
+    keysyms, xkb_compose_compile_flags, xkb_compose_feed_result, xkb_compose_state,
+    xkb_compose_state_flags, xkb_compose_status, xkb_compose_table, xkb_context, xkb_context_flags,
+    xkb_keymap, xkb_keymap_compile_flags, xkb_keysym_t, xkb_rule_names, xkb_state, XkbCommon,
+    XkbCommonCompose,
+};
+
+use super::event_loop::Atoms;
+use crate::{Key, KeyCode, KeyEvent, Result};
+
+// X11 keycodes are evdev scancodes offset by 8.
+fn key_code_from_keycode(keycode: Keycode) -> KeyCode {
+    match keycode.wrapping_sub(8) {
+        1 => KeyCode::Escape,
+        2 => KeyCode::Digit1,
+        3 => KeyCode::Digit2,
+        4 => KeyCode::Digit3,
+        5 => KeyCode::Digit4,
+        6 => KeyCode::Digit5,
+        7 => KeyCode::Digit6,
+        8 => KeyCode::Digit7,
+        9 => KeyCode::Digit8,
+        10 => KeyCode::Digit9,
+        11 => KeyCode::Digit0,
+        12 => KeyCode::Minus,
+        13 => KeyCode::Equal,
+        14 => KeyCode::Backspace,
+        15 => KeyCode::Tab,
+        16 => KeyCode::KeyQ,
+        17 => KeyCode::KeyW,
+        18 => KeyCode::KeyE,
+        19 => KeyCode::KeyR,
+        20 => KeyCode::KeyT,
+        21 => KeyCode::KeyY,
+        22 => KeyCode::KeyU,
+        23 => KeyCode::KeyI,
+        24 => KeyCode::KeyO,
+        25 => KeyCode::KeyP,
+        26 => KeyCode::BracketLeft,
+        27 => KeyCode::BracketRight,
+        28 => KeyCode::Enter,
+        29 => KeyCode::ControlLeft,
+        30 => KeyCode::KeyA,
+        31 => KeyCode::KeyS,
+        32 => KeyCode::KeyD,
+        33 => KeyCode::KeyF,
+        34 => KeyCode::KeyG,
+        35 => KeyCode::KeyH,
+        36 => KeyCode::KeyJ,
+        37 => KeyCode::KeyK,
+        38 => KeyCode::KeyL,
+        39 => KeyCode::Semicolon,
+        40 => KeyCode::Quote,
+        41 => KeyCode::Backquote,
+        42 => KeyCode::ShiftLeft,
+        43 => KeyCode::Backslash,
+        44 => KeyCode::KeyZ,
+        45 => KeyCode::KeyX,
+        46 => KeyCode::KeyC,
+        47 => KeyCode::KeyV,
+        48 => KeyCode::KeyB,
+        49 => KeyCode::KeyN,
+        50 => KeyCode::KeyM,
+        51 => KeyCode::Comma,
+        52 => KeyCode::Period,
+        53 => KeyCode::Slash,
+        54 => KeyCode::ShiftRight,
+        55 => KeyCode::NumpadMultiply,
+        56 => KeyCode::AltLeft,
+        57 => KeyCode::Space,
+        58 => KeyCode::CapsLock,
+        59 => KeyCode::F1,
+        60 => KeyCode::F2,
+        61 => KeyCode::F3,
+        62 => KeyCode::F4,
+        63 => KeyCode::F5,
+        64 => KeyCode::F6,
+        65 => KeyCode::F7,
+        66 => KeyCode::F8,
+        67 => KeyCode::F9,
+        68 => KeyCode::F10,
+        69 => KeyCode::NumLock,
+        70 => KeyCode::ScrollLock,
+        71 => KeyCode::Numpad7,
+        72 => KeyCode::Numpad8,
+        73 => KeyCode::Numpad9,
+        74 => KeyCode::NumpadSubtract,
+        75 => KeyCode::Numpad4,
+        76 => KeyCode::Numpad5,
+        77 => KeyCode::Numpad6,
+        78 => KeyCode::NumpadAdd,
+        79 => KeyCode::Numpad1,
+        80 => KeyCode::Numpad2,
+        81 => KeyCode::Numpad3,
+        82 => KeyCode::Numpad0,
+        83 => KeyCode::NumpadDecimal,
+        86 => KeyCode::IntlBackslash,
+        87 => KeyCode::F11,
+        88 => KeyCode::F12,
+        96 => KeyCode::NumpadEnter,
+        97 => KeyCode::ControlRight,
+        98 => KeyCode::NumpadDivide,
+        99 => KeyCode::PrintScreen,
+        100 => KeyCode::AltRight,
+        102 => KeyCode::Home,
+        103 => KeyCode::ArrowUp,
+        104 => KeyCode::PageUp,
+        105 => KeyCode::ArrowLeft,
+        106 => KeyCode::ArrowRight,
+        107 => KeyCode::End,
+        108 => KeyCode::ArrowDown,
+        109 => KeyCode::PageDown,
+        110 => KeyCode::Insert,
+        111 => KeyCode::Delete,
+        119 => KeyCode::Pause,
+        125 => KeyCode::MetaLeft,
+        126 => KeyCode::MetaRight,
+        127 => KeyCode::ContextMenu,
+        _ => KeyCode::Unidentified,
+    }
+}
+
+fn key_from_keysym(xkb: &XkbCommon, keysym: u32) -> Key {
+    match keysym {
+        keysyms::Return | keysyms::KP_Enter => Key::Enter,
+        keysyms::Tab | keysyms::KP_Tab | keysyms::ISO_Left_Tab => Key::Tab,
+        keysyms::BackSpace => Key::Backspace,
+        keysyms::Escape => Key::Escape,
+        keysyms::Delete | keysyms::KP_Delete => Key::Delete,
+        keysyms::Insert | keysyms::KP_Insert => Key::Insert,
+        keysyms::Home | keysyms::KP_Home => Key::Home,
+        keysyms::End | keysyms::KP_End => Key::End,
+        keysyms::Prior | keysyms::KP_Prior => Key::PageUp,
+        keysyms::Next | keysyms::KP_Next => Key::PageDown,
+        keysyms::Up | keysyms::KP_Up => Key::ArrowUp,
+        keysyms::Down | keysyms::KP_Down => Key::ArrowDown,
+        keysyms::Left | keysyms::KP_Left => Key::ArrowLeft,
+        keysyms::Right | keysyms::KP_Right => Key::ArrowRight,
+        keysyms::Shift_L | keysyms::Shift_R => Key::Shift,
+        keysyms::Control_L | keysyms::Control_R => Key::Control,
+        keysyms::Alt_L | keysyms::Alt_R => Key::Alt,
+        keysyms::Meta_L | keysyms::Meta_R | keysyms::Super_L | keysyms::Super_R => Key::Meta,
+        keysyms::Caps_Lock => Key::CapsLock,
+        keysyms::Num_Lock => Key::NumLock,
+        keysyms::Scroll_Lock => Key::ScrollLock,
+        keysyms::Menu => Key::ContextMenu,
+        keysyms::Print => Key::PrintScreen,
+        keysyms::Pause => Key::Pause,
+        keysyms::F1 => Key::F1,
+        keysyms::F2 => Key::F2,
+        keysyms::F3 => Key::F3,
+        keysyms::F4 => Key::F4,
+        keysyms::F5 => Key::F5,
+        keysyms::F6 => Key::F6,
+        keysyms::F7 => Key::F7,
+        keysyms::F8 => Key::F8,
+        keysyms::F9 => Key::F9,
+        keysyms::F10 => Key::F10,
+        keysyms::F11 => Key::F11,
+        keysyms::F12 => Key::F12,
+        _ => {
+            let code_point = unsafe { (xkb.xkb_keysym_to_utf32)(keysym) };
+            match char::from_u32(code_point) {
+                Some(c) if !c.is_control() => Key::Char(c),
+                _ => Key::Unidentified,
+            }
+        }
+    }
+}
+
+// Reads the RMLVO names that the server's keymap was compiled from, as set by setxkbmap. These are
+// only used if the keymap can't be fetched from the server, in which case remappings made with
+// xmodmap or xkbcomp are not seen.
+fn get_rule_names(
+    connection: &RustConnection,
+    screen_index: usize,
+    atoms: &Atoms,
+) -> Result<Vec<CString>> {
+    let root = connection.setup().roots[screen_index].root;
+    let reply = connection
+        .get_property(
+            false,
+            root,
+            atoms._XKB_RULES_NAMES,
+            AtomEnum::STRING,
+            0,
+            1024,
+        )?
+        .reply()?;
+
+    let names = reply
+        .value
+        .split(|b| *b == 0)
+        .take(5)
+        .map(|name| CString::new(name).unwrap())
+        .collect();
+
+    Ok(names)
+}
+
+pub fn locale() -> String {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_else(|| "C".to_string())
+}
+
+// Calls an xkbcommon function that writes a NUL-terminated UTF-8 string into a buffer and returns
+// the length it needs.
+fn get_utf8<F>(f: F) -> Option<String>
+where
+    F: Fn(*mut c_char, usize) -> c_int,
+{
+    let len = f(ptr::null_mut(), 0);
+    if len <= 0 {
+        return None;
+    }
+
+    let mut buffer = vec![0u8; len as usize + 1];
+    f(buffer.as_mut_ptr() as *mut c_char, buffer.len());
+    buffer.truncate(len as usize);
+
+    String::from_utf8(buffer).ok()
+}
+
+struct Keymap {
+    xkb: &'static XkbCommon,
+    context: *mut xkb_context,
+    keymap: *mut xkb_keymap,
+    state: *mut xkb_state,
+}
+
+impl Keymap {
+    // Fetches the keymap of the core keyboard from the server. xkbcommon can only do this over an
+    // XCB connection, so a separate one is opened for it.
+    fn from_server(xkb: &'static XkbCommon, xkb_x11: &'static XkbCommonX11) -> Option<Keymap> {
+        let (connection, _) = XCBConnection::connect(None).ok()?;
+        let raw_connection = connection.get_raw_xcb_connection();
+
+        unsafe {
+            let result = (xkb_x11.xkb_x11_setup_xkb_extension)(
+                raw_connection,
+                1,
+                0,
+                xkb_x11_setup_xkb_extension_flags::XKB_X11_SETUP_XKB_EXTENSION_NO_FLAGS,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            if result == 0 {
+                return None;
+            }
+
+            let device_id = (xkb_x11.xkb_x11_get_core_keyboard_device_id)(raw_connection);
+            if device_id == -1 {
+                return None;
+            }
+
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                return None;
+            }
+
+            // The keymap doesn't refer to the connection once it has been created.
+            let keymap = (xkb_x11.xkb_x11_keymap_new_from_device)(
+                context,
+                raw_connection,
+                device_id,
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            );
+
+            Self::from_keymap(xkb, context, keymap)
+        }
+    }
+
+    fn from_names(xkb: &'static XkbCommon, names: &[CString]) -> Option<Keymap> {
+        fn name_ptr(names: &[CString], index: usize) -> *const c_char {
+            match names.get(index) {
+                Some(name) if !name.as_bytes().is_empty() => name.as_ptr(),
+                _ => ptr::null(),
+            }
+        }
+
+        let rule_names = xkb_rule_names {
+            rules: name_ptr(names, 0),
+            model: name_ptr(names, 1),
+            layout: name_ptr(names, 2),
+            variant: name_ptr(names, 3),
+            options: name_ptr(names, 4),
+        };
+
+        unsafe {
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                return None;
+            }
+
+            let keymap = (xkb.xkb_keymap_new_from_names)(
+                context,
+                &rule_names,
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            );
+
+            Self::from_keymap(xkb, context, keymap)
+        }
+    }
+
+    // Takes ownership of the context and keymap, which are freed if the state can't be created.
+    unsafe fn from_keymap(
+        xkb: &'static XkbCommon,
+        context: *mut xkb_context,
+        keymap: *mut xkb_keymap,
+    ) -> Option<Keymap> {
+        unsafe {
+            if keymap.is_null() {
+                (xkb.xkb_context_unref)(context);
+                return None;
+            }
+
+            let state = (xkb.xkb_state_new)(keymap);
+            if state.is_null() {
+                (xkb.xkb_keymap_unref)(keymap);
+                (xkb.xkb_context_unref)(context);
+                return None;
+            }
+
+            Some(Keymap {
+                xkb,
+                context,
+                keymap,
+                state,
+            })
+        }
+    }
+
+    fn update_mask(&self, mods: [u32; 3], groups: [u32; 3]) {
+        unsafe {
+            (self.xkb.xkb_state_update_mask)(
+                self.state, mods[0], mods[1], mods[2], groups[0], groups[1], groups[2],
+            );
+        }
+    }
+
+    fn keysym(&self, keycode: Keycode) -> xkb_keysym_t {
+        unsafe { (self.xkb.xkb_state_key_get_one_sym)(self.state, keycode as u32) }
+    }
+
+    fn key(&self, keycode: Keycode) -> Key {
+        key_from_keysym(self.xkb, self.keysym(keycode))
+    }
+
+    fn text(&self, keycode: Keycode) -> Option<String> {
+        get_utf8(|buffer, size| unsafe {
+            (self.xkb.xkb_state_key_get_utf8)(self.state, keycode as u32, buffer, size)
+        })
+    }
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xkb.xkb_state_unref)(self.state);
+            (self.xkb.xkb_keymap_unref)(self.keymap);
+            (self.xkb.xkb_context_unref)(self.context);
+        }
+    }
+}
+
+enum ComposeResult {
+    Nothing,
+    Composing,
+    Composed(Option<String>),
+    Cancelled,
+}
+
+struct Compose {
+    xkb: &'static XkbCommonCompose,
+    table: *mut xkb_compose_table,
+    state: *mut xkb_compose_state,
+}
+
+impl Compose {
+    fn new(xkb: &'static XkbCommon, xkb_compose: &'static XkbCommonCompose) -> Option<Compose> {
+        let locale = CString::new(locale()).ok()?;
+
+        unsafe {
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                return None;
+            }
+
+            // The table keeps its own reference to the context.
+            let table = (xkb_compose.xkb_compose_table_new_from_locale)(
+                context,
+                locale.as_ptr(),
+                xkb_compose_compile_flags::XKB_COMPOSE_COMPILE_NO_FLAGS,
+            );
+            (xkb.xkb_context_unref)(context);
+            if table.is_null() {
+                return None;
+            }
+
+            let state = (xkb_compose.xkb_compose_state_new)(
+                table,
+                xkb_compose_state_flags::XKB_COMPOSE_STATE_NO_FLAGS,
+            );
+            if state.is_null() {
+                (xkb_compose.xkb_compose_table_unref)(table);
+                return None;
+            }
+
+            Some(Compose {
+                xkb: xkb_compose,
+                table,
+                state,
+            })
+        }
+    }
+
+    fn feed(&self, keysym: xkb_keysym_t) -> ComposeResult {
+        unsafe {
+            let result = (self.xkb.xkb_compose_state_feed)(self.state, keysym);
+            if result == xkb_compose_feed_result::XKB_COMPOSE_FEED_IGNORED {
+                return ComposeResult::Nothing;
+            }
+
+            match (self.xkb.xkb_compose_state_get_status)(self.state) {
+                xkb_compose_status::XKB_COMPOSE_NOTHING => ComposeResult::Nothing,
+                xkb_compose_status::XKB_COMPOSE_COMPOSING => ComposeResult::Composing,
+                xkb_compose_status::XKB_COMPOSE_COMPOSED => {
+                    let text = get_utf8(|buffer, size| {
+                        (self.xkb.xkb_compose_state_get_utf8)(self.state, buffer, size)
+                    });
+                    (self.xkb.xkb_compose_state_reset)(self.state);
+                    ComposeResult::Composed(text)
+                }
+                xkb_compose_status::XKB_COMPOSE_CANCELLED => {
+                    (self.xkb.xkb_compose_state_reset)(self.state);
+                    ComposeResult::Cancelled
+                }
+            }
+        }
+    }
+
+    fn reset(&self) {
+        unsafe { (self.xkb.xkb_compose_state_reset)(self.state) };
+    }
+}
+
+impl Drop for Compose {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xkb.xkb_compose_state_unref)(self.state);
+            (self.xkb.xkb_compose_table_unref)(self.table);
+        }
+    }
+}
+
+pub struct Keyboard {
+    xkb_supported: bool,
+    keymap: RefCell<Option<Keymap>>,
+    compose: Option<Compose>,
+    pressed: RefCell<HashSet<Keycode>>,
+}
+
+impl Keyboard {
+    pub fn new(
+        connection: &RustConnection,
+        screen_index: usize,
+        atoms: &Atoms,
+    ) -> Result<Keyboard> {
+        let xkb_supported = if connection.extension_information(xkb::X11_EXTENSION_NAME)?.is_some()
+        {
+            connection.xkb_use_extension(1, 0)?.reply()?.supported
+        } else {
+            false
+        };
+
+        let compose = xkbcommon_dl::xkbcommon_option()
+            .zip(xkbcommon_dl::xkbcommon_compose_option())
+            .and_then(|(xkb, xkb_compose)| Compose::new(xkb, xkb_compose));
+
+        let keyboard = Keyboard {
+            xkb_supported,
+            keymap: RefCell::new(None),
+            compose,
+            pressed: RefCell::new(HashSet::new()),
+        };
+
+        if !xkb_supported {
+            return Ok(keyboard);
+        }
+
+        let events = xkb::EventType::NEW_KEYBOARD_NOTIFY
+            | xkb::EventType::MAP_NOTIFY
+            | xkb::EventType::STATE_NOTIFY;
+        let map_parts = xkb::MapPart::KEY_TYPES
+            | xkb::MapPart::KEY_SYMS
+            | xkb::MapPart::MODIFIER_MAP
+            | xkb::MapPart::EXPLICIT_COMPONENTS
+            | xkb::MapPart::KEY_ACTIONS
+            | xkb::MapPart::VIRTUAL_MODS
+            | xkb::MapPart::VIRTUAL_MOD_MAP;
+        connection.xkb_select_events(
+            xkb::ID::USE_CORE_KBD.into(),
+            0u16.into(),
+            events,
+            map_parts,
+            map_parts,
+            &xkb::SelectEventsAux::new(),
+        )?;
+
+        // With detectable auto-repeat, held keys generate repeated presses without intervening
+        // releases, which lets us distinguish repeats from actual presses.
+        connection
+            .xkb_per_client_flags(
+                xkb::ID::USE_CORE_KBD.into(),
+                xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+                xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+                0u32.into(),
+                0u32.into(),
+                0u32.into(),
+            )?
+            .reply()?;
+
+        keyboard.update_keymap(connection, screen_index, atoms)?;
+
+        Ok(keyboard)
+    }
+
+    pub fn update_keymap(
+        &self,
+        connection: &RustConnection,
+        screen_index: usize,
+        atoms: &Atoms,
+    ) -> Result<()> {
+        if !self.xkb_supported {
+            return Ok(());
+        }
+
+        let Some(xkb) = xkbcommon_dl::xkbcommon_option() else {
+            return Ok(());
+        };
+
+        let keymap = match xkbcommon_dl::x11::xkbcommon_x11_option()
+            .and_then(|xkb_x11| Keymap::from_server(xkb, xkb_x11))
+        {
+            Some(keymap) => Some(keymap),
+            None => {
+                let names = get_rule_names(connection, screen_index, atoms)?;
+                Keymap::from_names(xkb, &names)
+            }
+        };
+
+        if let Some(keymap) = &keymap {
+            let state = connection.xkb_get_state(xkb::ID::USE_CORE_KBD.into())?.reply()?;
+            keymap.update_mask(
+                [
+                    u16::from(state.base_mods) as u32,
+                    u16::from(state.latched_mods) as u32,
+                    u16::from(state.locked_mods) as u32,
+                ],
+                [
+                    state.base_group as u32,
+                    state.latched_group as u32,
+                    u8::from(state.locked_group) as u32,
+                ],
+            );
+        }
+
+        self.keymap.replace(keymap);
+
+        Ok(())
+    }
+
+    pub fn update_state(&self, event: &xkb::StateNotifyEvent) {
+        if let Some(keymap) = &*self.keymap.borrow() {
+            keymap.update_mask(
+                [
+                    u16::from(event.base_mods) as u32,
+                    u16::from(event.latched_mods) as u32,
+                    u16::from(event.locked_mods) as u32,
+                ],
+                [
+                    event.base_group as u32,
+                    event.latched_group as u32,
+                    u8::from(event.locked_group) as u32,
+                ],
+            );
+        }
+    }
+
+    fn key(&self, keycode: Keycode) -> Key {
+        if let Some(keymap) = &*self.keymap.borrow() {
+            keymap.key(keycode)
+        } else {
+            Key::Unidentified
+        }
+    }
+
+    pub fn key_down(&self, keycode: Keycode) -> KeyEvent {
+        let repeat = !self.pressed.borrow_mut().insert(keycode);
+
+        KeyEvent {
+            key: self.key(keycode),
+            code: key_code_from_keycode(keycode),
+            repeat,
+        }
+    }
+
+    pub fn key_up(&self, keycode: Keycode) -> KeyEvent {
+        self.pressed.borrow_mut().remove(&keycode);
+
+        KeyEvent {
+            key: self.key(keycode),
+            code: key_code_from_keycode(keycode),
+            repeat: false,
+        }
+    }
+
+    // Returns the text produced by a key press, if any. Dead keys and compose sequences produce
+    // no text until the sequence is complete.
+    pub fn text(&self, keycode: Keycode) -> Option<String> {
+        let keymap = self.keymap.borrow();
+        let keymap = keymap.as_ref()?;
+
+        if let Some(compose) = &self.compose {
+            match compose.feed(keymap.keysym(keycode)) {
+                ComposeResult::Nothing => {}
+                ComposeResult::Composing | ComposeResult::Cancelled => return None,
+                ComposeResult::Composed(text) => return text,
+            }
+        }
+
+        keymap.text(keycode).filter(|text| !text.chars().any(char::is_control))
+    }
+
+    pub fn reset_compose(&self) {
+        if let Some(compose) = &self.compose {
+            compose.reset();
+        }
+    }
+
+    // Keys released while another window has focus never send a release to us, so they would
+    // otherwise be reported as repeats when pressed again.
+    pub fn reset_pressed(&self) {
+        self.pressed.borrow_mut().clear();
+    }
+}
@@ -1,7 +1,12 @@
 mod error;
 mod event_loop;
+mod frame_clock;
+mod ime;
+mod keyboard;
+mod pointer;
 mod timer;
 mod window;
+mod xsettings;
 
 pub use error::OsError;
 pub use event_loop::EventLoopState;
@@ -0,0 +1,456 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::xinput::{self, ConnectionExt as _, DeviceClassData, DeviceId};
+use x11rb::protocol::xproto::{
+    ConnectionExt as _, Cursor, EventMask, GrabMode, GrabStatus, Timestamp, Window as WindowId,
+};
+use x11rb::rust_connection::RustConnection;
+
+use crate::{Point, Result};
+
+pub fn fp1616_to_f64(value: xinput::Fp1616) -> f64 {
+    value as f64 / (1 << 16) as f64
+}
+
+pub fn fp3232_to_f64(value: xinput::Fp3232) -> f64 {
+    value.integral as f64 + value.frac as f64 / (1u64 << 32) as f64
+}
+
+struct ScrollAxis {
+    number: u16,
+    horizontal: bool,
+    increment: f64,
+    // Scroll valuators are absolute, so deltas are computed relative to the last value seen.
+    position: Option<f64>,
+}
+
+#[derive(Copy, Clone)]
+struct Lock {
+    window: WindowId,
+    // The pointer position to restore when unlocking.
+    restore_x: i16,
+    restore_y: i16,
+    center_x: i16,
+    center_y: i16,
+}
+
+#[derive(Copy, Clone)]
+struct Capture {
+    window: WindowId,
+    // The button whose release ends the capture.
+    button: u32,
+}
+
+// Pointer input via XInput 2, which provides smooth scrolling and raw motion.
+pub struct Pointer {
+    xi2_supported: bool,
+    root: WindowId,
+    scroll_axes: RefCell<HashMap<DeviceId, Vec<ScrollAxis>>>,
+    lock: Cell<Option<Lock>>,
+    capture: Cell<Option<Capture>>,
+    // The window the pointer is currently in, according to the crossing events seen so far.
+    hovered: Cell<Option<WindowId>>,
+}
+
+impl Pointer {
+    pub fn new(connection: &RustConnection, screen_index: usize) -> Result<Pointer> {
+        // Smooth scrolling was added in XInput 2.1.
+        let xi2_supported =
+            if connection.extension_information(xinput::X11_EXTENSION_NAME)?.is_some() {
+                let version = connection.xinput_xi_query_version(2, 1)?.reply()?;
+                (version.major_version, version.minor_version) >= (2, 1)
+            } else {
+                false
+            };
+
+        let pointer = Pointer {
+            xi2_supported,
+            root: connection.setup().roots[screen_index].root,
+            scroll_axes: RefCell::new(HashMap::new()),
+            lock: Cell::new(None),
+            capture: Cell::new(None),
+            hovered: Cell::new(None),
+        };
+
+        if xi2_supported {
+            connection.xinput_xi_select_events(
+                pointer.root,
+                &[xinput::EventMask {
+                    deviceid: xinput::Device::ALL.into(),
+                    mask: vec![
+                        xinput::XIEventMask::HIERARCHY | xinput::XIEventMask::DEVICE_CHANGED,
+                    ],
+                }],
+            )?;
+
+            pointer.update_devices(connection)?;
+        }
+
+        Ok(pointer)
+    }
+
+    // Once XInput 2 events are selected for a window, the corresponding core events are no longer
+    // delivered for it.
+    pub fn select_events(&self, connection: &RustConnection, window: WindowId) -> Result<()> {
+        if self.xi2_supported {
+            connection.xinput_xi_select_events(
+                window,
+                &[xinput::EventMask {
+                    deviceid: xinput::Device::ALL_MASTER.into(),
+                    mask: vec![
+                        xinput::XIEventMask::BUTTON_PRESS
+                            | xinput::XIEventMask::BUTTON_RELEASE
+                            | xinput::XIEventMask::MOTION,
+                    ],
+                }],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update_devices(&self, connection: &RustConnection) -> Result<()> {
+        if !self.xi2_supported {
+            return Ok(());
+        }
+
+        let reply = connection.xinput_xi_query_device(xinput::Device::ALL)?.reply()?;
+
+        let mut scroll_axes = HashMap::new();
+        for info in reply.infos {
+            let axes: Vec<ScrollAxis> = info
+                .classes
+                .iter()
+                .filter_map(|class| match &class.data {
+                    DeviceClassData::Scroll(scroll) if fp3232_to_f64(scroll.increment) != 0.0 => {
+                        Some(ScrollAxis {
+                            number: scroll.number,
+                            horizontal: scroll.scroll_type == xinput::ScrollType::HORIZONTAL,
+                            increment: fp3232_to_f64(scroll.increment),
+                            position: None,
+                        })
+                    }
+                    _ => None,
+                })
+                .collect();
+
+            if !axes.is_empty() {
+                scroll_axes.insert(info.deviceid, axes);
+            }
+        }
+
+        self.scroll_axes.replace(scroll_axes);
+
+        Ok(())
+    }
+
+    // Valuators may change while the pointer is outside of our windows, so the next value seen
+    // should not produce a delta.
+    pub fn reset_scroll_positions(&self) {
+        for axes in self.scroll_axes.borrow_mut().values_mut() {
+            for axis in axes {
+                axis.position = None;
+            }
+        }
+    }
+
+    // Returns the scroll delta in lines for a motion event, if it contains changes to any scroll
+    // valuators.
+    pub fn scroll_delta(&self, event: &xinput::MotionEvent) -> Option<Point> {
+        let mut scroll_axes = self.scroll_axes.borrow_mut();
+        let axes = scroll_axes.get_mut(&event.sourceid)?;
+
+        let mut delta = Point::new(0.0, 0.0);
+        let mut scrolled = false;
+
+        let mut values = event.axisvalues.iter();
+        for number in 0..event.valuator_mask.len() as u16 * 32 {
+            let mask = event.valuator_mask[number as usize / 32];
+            if mask & (1 << (number % 32)) == 0 {
+                continue;
+            }
+
+            let Some(value) = values.next() else {
+                break;
+            };
+            let value = fp3232_to_f64(*value);
+
+            let Some(axis) = axes.iter_mut().find(|axis| axis.number == number) else {
+                continue;
+            };
+
+            if let Some(position) = axis.position.replace(value) {
+                let lines = (value - position) / axis.increment;
+                if axis.horizontal {
+                    delta.x += lines;
+                } else {
+                    // Positive vertical valuator motion is downward, whereas positive scroll
+                    // deltas are upward.
+                    delta.y -= lines;
+                }
+                scrolled = true;
+            }
+        }
+
+        if scrolled {
+            Some(delta)
+        } else {
+            None
+        }
+    }
+
+    // Whether a motion event moves the pointer, as opposed to only changing scroll valuators.
+    // Valuators 0 and 1 are always the X and Y axes.
+    pub fn has_motion(&self, event: &xinput::MotionEvent) -> bool {
+        event.valuator_mask.first().is_some_and(|mask| mask & 0b11 != 0)
+    }
+
+    pub fn locked_window(&self) -> Option<WindowId> {
+        self.lock.get().map(|lock| lock.window)
+    }
+
+    // Confines the pointer to the window and hides it. Raw motion events can only be selected on
+    // the root window, so they are only selected while the pointer is locked.
+    pub fn lock(
+        &self,
+        connection: &RustConnection,
+        window: WindowId,
+        cursor: Cursor,
+    ) -> Result<()> {
+        if self.lock.get().is_some() {
+            self.unlock(connection)?;
+        }
+
+        let pointer = connection.query_pointer(window)?.reply()?;
+        let geometry = connection.get_geometry(window)?.reply()?;
+
+        let reply = connection
+            .grab_pointer(
+                false,
+                window,
+                EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE | EventMask::POINTER_MOTION,
+                GrabMode::ASYNC,
+                GrabMode::ASYNC,
+                window,
+                cursor,
+                x11rb::CURRENT_TIME,
+            )?
+            .reply()?;
+        if reply.status != GrabStatus::SUCCESS {
+            return Ok(());
+        }
+
+        let lock = Lock {
+            window,
+            restore_x: pointer.win_x,
+            restore_y: pointer.win_y,
+            center_x: (geometry.width / 2) as i16,
+            center_y: (geometry.height / 2) as i16,
+        };
+        self.lock.set(Some(lock));
+
+        if self.xi2_supported {
+            self.select_raw_motion(connection, true)?;
+        } else {
+            connection.warp_pointer(
+                x11rb::NONE,
+                window,
+                0,
+                0,
+                0,
+                0,
+                lock.center_x,
+                lock.center_y,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn unlock(&self, connection: &RustConnection) -> Result<()> {
+        let Some(lock) = self.lock.take() else {
+            return Ok(());
+        };
+
+        if self.xi2_supported {
+            self.select_raw_motion(connection, false)?;
+        }
+
+        // Releasing the grab also ends any capture that started while the pointer was locked.
+        self.capture.set(None);
+        connection.ungrab_pointer(x11rb::CURRENT_TIME)?;
+        connection.warp_pointer(
+            x11rb::NONE,
+            lock.window,
+            0,
+            0,
+            0,
+            0,
+            lock.restore_x,
+            lock.restore_y,
+        )?;
+
+        Ok(())
+    }
+
+    fn select_raw_motion(&self, connection: &RustConnection, enabled: bool) -> Result<()> {
+        let mask = if enabled {
+            xinput::XIEventMask::RAW_MOTION
+        } else {
+            xinput::XIEventMask::from(0u32)
+        };
+
+        connection.xinput_xi_select_events(
+            self.root,
+            &[xinput::EventMask {
+                deviceid: xinput::Device::ALL_MASTER.into(),
+                mask: vec![mask],
+            }],
+        )?;
+
+        Ok(())
+    }
+
+    // Returns the unaccelerated motion of the x and y valuators in a raw motion event.
+    pub fn raw_motion_delta(&self, event: &xinput::RawMotionEvent) -> Point {
+        let mut delta = Point::new(0.0, 0.0);
+
+        let mut values = event.axisvalues_raw.iter();
+        for number in 0..2 {
+            let mask = event.valuator_mask.first().copied().unwrap_or(0);
+            if mask & (1 << number) == 0 {
+                continue;
+            }
+
+            let Some(value) = values.next() else {
+                break;
+            };
+
+            if number == 0 {
+                delta.x = fp3232_to_f64(*value);
+            } else {
+                delta.y = fp3232_to_f64(*value);
+            }
+        }
+
+        delta
+    }
+
+    // Without XInput 2, relative motion is emulated by warping the pointer back to the center of
+    // the window after every motion event. Returns the motion relative to the center, or None
+    // for the motion event generated by the warp itself.
+    pub fn warp_motion_delta(
+        &self,
+        connection: &RustConnection,
+        x: i16,
+        y: i16,
+    ) -> Result<Option<Point>> {
+        let Some(lock) = self.lock.get() else {
+            return Ok(None);
+        };
+
+        if x == lock.center_x && y == lock.center_y {
+            return Ok(None);
+        }
+
+        connection.warp_pointer(
+            x11rb::NONE,
+            lock.window,
+            0,
+            0,
+            0,
+            0,
+            lock.center_x,
+            lock.center_y,
+        )?;
+        connection.flush()?;
+
+        let delta = Point::new((x - lock.center_x) as f64, (y - lock.center_y) as f64);
+        Ok(Some(delta))
+    }
+
+    pub fn captured_window(&self) -> Option<WindowId> {
+        self.capture.get().map(|capture| capture.window)
+    }
+
+    // Grabs the pointer so that motion and the release of the given button are reported to the
+    // window even after the pointer leaves it. The implicit grab X sets up on a button press would
+    // end with the release of any button, and crossing events would still be reported.
+    pub fn capture(
+        &self,
+        connection: &RustConnection,
+        window: WindowId,
+        button: u32,
+        time: Timestamp,
+    ) -> Result<()> {
+        if self.capture.get().is_some() {
+            return Ok(());
+        }
+
+        // The pointer lock's grab already confines the pointer to the window.
+        if self.lock.get().is_none() {
+            let reply = connection
+                .grab_pointer(
+                    false,
+                    window,
+                    EventMask::BUTTON_PRESS
+                        | EventMask::BUTTON_RELEASE
+                        | EventMask::POINTER_MOTION
+                        | EventMask::ENTER_WINDOW
+                        | EventMask::LEAVE_WINDOW,
+                    GrabMode::ASYNC,
+                    GrabMode::ASYNC,
+                    x11rb::NONE,
+                    x11rb::NONE,
+                    time,
+                )?
+                .reply()?;
+            if reply.status != GrabStatus::SUCCESS {
+                return Ok(());
+            }
+        }
+
+        self.capture.set(Some(Capture { window, button }));
+
+        Ok(())
+    }
+
+    // Ends the capture if it was started by the given button in the given window. Returns whether
+    // the capture ended.
+    pub fn release_capture(
+        &self,
+        connection: &RustConnection,
+        window: WindowId,
+        button: u32,
+    ) -> Result<bool> {
+        match self.capture.get() {
+            Some(capture) if capture.window == window && capture.button == button => {
+                self.end_capture(connection)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn end_capture(&self, connection: &RustConnection) -> Result<()> {
+        if self.capture.take().is_some() && self.lock.get().is_none() {
+            connection.ungrab_pointer(x11rb::CURRENT_TIME)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn hovered_window(&self) -> Option<WindowId> {
+        self.hovered.get()
+    }
+
+    pub fn set_hovered_window(&self, window: Option<WindowId>) {
+        self.hovered.set(window);
+    }
+
+    pub fn xi2_supported(&self) -> bool {
+        self.xi2_supported
+    }
+}
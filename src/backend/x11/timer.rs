@@ -73,7 +73,7 @@ impl Eq for QueueEntry {}
 
 impl PartialOrd for QueueEntry {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.time.cmp(&other.time).reverse())
+        Some(self.cmp(other))
     }
 }
 
@@ -106,7 +106,7 @@ impl Timers {
         let now = Instant::now();
 
         // Check with < and not <= so that we don't process a timer twice during this loop
-        while self.next_time().map_or(false, |t| t < now) {
+        while self.next_time().is_some_and(|t| t < now) {
             let next = self.queue.borrow_mut().pop().unwrap();
 
             // If we don't find the timer in `self.timers`, it has been canceled
@@ -1,62 +1,266 @@
 use std::cell::{Cell, RefCell};
-use std::ffi::{c_int, c_ulong, c_void};
+use std::collections::HashSet;
+use std::ffi::{c_ulong, c_void};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::{mem, ptr, slice};
 
-use x11rb::connection::Connection;
+use x11rb::connection::{Connection, DiscardMode, RequestConnection, RequestKind, SequenceNumber};
+use x11rb::properties::WmSizeHints;
+use x11rb::protocol;
 use x11rb::protocol::present::{self, ConnectionExt as _};
+use x11rb::protocol::render::{
+    ConnectionExt as _, CreatePictureAux, Fixed, PictOp, Pictformat, Picture, Transform,
+};
 use x11rb::protocol::shm::{ConnectionExt as _, Seg};
 use x11rb::protocol::xproto::{
-    AtomEnum, ChangeWindowAttributesAux, ClipOrdering, ConnectionExt as _, CreateGCAux,
-    CreateWindowAux, EventMask, Gcontext, ImageFormat, PropMode, Rectangle, Window, WindowClass,
+    AtomEnum, ChangeGCAux, ChangeWindowAttributesAux, ClipOrdering, Colormap, ColormapAlloc,
+    ConfigureWindowAux, ConnectionExt as _, CreateGCAux, CreateWindowAux, Drawable, EventMask,
+    Gcontext, ImageFormat, InputFocus, Keycode, MapState, Pixmap, PropMode, Rectangle, Window,
+    WindowClass,
 };
 use x11rb::wrapper::ConnectionExt as _;
 
 use super::event_loop::EventLoopState;
 use super::OsError;
 use crate::{
-    Bitmap, Cursor, Error, Event, EventLoop, Point, RawWindow, Rect, Response, Result, Size,
-    WindowOptions,
+    Bitmap, BitmapMut, Cursor, Error, Event, EventLoop, FrameInfo, OwnedBitmap, PixelFormat, Point,
+    RawWindow, Rect, Response, Result, Scaling, Size, WindowOptions,
 };
 
+// How long present waits for the server to release a buffer before drawing to the window directly.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
 pub struct ShmState {
-    shm_id: c_int,
     seg_id: Seg,
     ptr: *mut c_void,
+    // The size of the segment in pixels.
+    capacity: usize,
+    // Whether the memory is a mapping of a memfd rather than a SysV segment.
+    memfd: bool,
+    // A pixmap backed by the segment, used with PresentPixmap.
+    pixmap: Option<ShmPixmap>,
+    // Whether the server may still read from the pixmap, which lasts until IdleNotify.
+    busy: bool,
+    // A request sent after the last ShmPutImage from this buffer. Once its reply has arrived, the
+    // server is done reading from the buffer.
+    fence: Option<SequenceNumber>,
+}
+
+struct ShmPixmap {
+    id: Pixmap,
     width: usize,
     height: usize,
 }
 
+#[derive(Copy, Clone)]
+struct SizeConstraints {
+    resizable: bool,
+    min_size: Option<Size>,
+    max_size: Option<Size>,
+}
+
+impl SizeConstraints {
+    // A window which isn't resizable has its minimum and maximum size set to its current size.
+    fn size_hints(&self, size_physical: Size, scale: f64) -> WmSizeHints {
+        let to_physical = |size: Size| {
+            let size_physical = size.scale(scale);
+            (
+                size_physical.width.round() as i32,
+                size_physical.height.round() as i32,
+            )
+        };
+
+        let mut hints = WmSizeHints::new();
+        if self.resizable {
+            hints.min_size = self.min_size.map(to_physical);
+            hints.max_size = self.max_size.map(to_physical);
+        } else {
+            let size = (
+                size_physical.width.round() as i32,
+                size_physical.height.round() as i32,
+            );
+            hints.min_size = Some(size);
+            hints.max_size = Some(size);
+        }
+
+        hints
+    }
+}
+
+// Where a bitmap of the given size ends up in the window, as x, y, width and height.
+fn scaled_rect(
+    scaling: Scaling,
+    bitmap_size: (usize, usize),
+    window_size: (usize, usize),
+) -> (f64, f64, f64, f64) {
+    let (bitmap_width, bitmap_height) = (bitmap_size.0 as f64, bitmap_size.1 as f64);
+    let (window_width, window_height) = (window_size.0 as f64, window_size.1 as f64);
+
+    let scale = match scaling {
+        Scaling::None => return (0.0, 0.0, bitmap_width, bitmap_height),
+        Scaling::Stretch => return (0.0, 0.0, window_width, window_height),
+        Scaling::Fit => (window_width / bitmap_width).min(window_height / bitmap_height),
+        Scaling::Fill => (window_width / bitmap_width).max(window_height / bitmap_height),
+    };
+
+    let width = bitmap_width * scale;
+    let height = bitmap_height * scale;
+
+    (
+        (window_width - width) / 2.0,
+        (window_height - height) / 2.0,
+        width,
+        height,
+    )
+}
+
+struct ScaleSource {
+    pixmap: Pixmap,
+    picture: Picture,
+    width: usize,
+    height: usize,
+}
+
+// A copy of the last presented bitmap of a retained window.
+struct Backing {
+    pixmap: Pixmap,
+    width: usize,
+    height: usize,
+}
+
+// Used to composite scaled bitmaps onto the window with the RENDER extension.
+pub struct RenderState {
+    format: Pictformat,
+    window_picture: Picture,
+    source: Option<ScaleSource>,
+}
+
 pub struct PresentState {
     event_id: present::Event,
 }
 
+#[allow(clippy::type_complexity)]
 pub struct WindowState {
     pub window_id: Cell<Option<Window>>,
+    // Only set for embedded windows, which forward ignored input events to their parent.
+    pub parent_id: Option<Window>,
+    // The position of the window in its parent, used to translate events forwarded to it.
+    pub position: Cell<(i16, i16)>,
+    // Whether the handler ignored the most recent scroll event.
+    pub scroll_ignored: Cell<bool>,
+    // Keys whose presses were forwarded to the parent, so that only their releases follow.
+    pub forwarded_keys: RefCell<HashSet<Keycode>>,
+    size_constraints: SizeConstraints,
+    // The last size reported by ConfigureNotify, in physical pixels.
+    pub size: Cell<Size>,
     pub gc_id: Cell<Option<Gcontext>>,
-    pub shm_state: RefCell<Option<ShmState>>,
+    // 32 for transparent windows, which have their own colormap, and 24 otherwise.
+    depth: u8,
+    colormap: Option<Colormap>,
+    // The format of the pixels of images sent to the server.
+    pixel_format: PixelFormat,
+    // Shared memory buffers are used in turn, so that the next frame can be drawn while the server
+    // may still be reading the previous one.
+    pub shm_buffers: [RefCell<Option<ShmState>>; 3],
+    pub shm_index: Cell<usize>,
+    // Used by with_buffer when shared memory isn't available.
+    fallback_buffer: RefCell<Vec<u32>>,
     pub present_state: RefCell<Option<PresentState>>,
+    scaling: Cell<Scaling>,
+    render_state: RefCell<Option<RenderState>>,
+    // Whether the last bitmap was presented through the offscreen pixmap.
+    presented_scaled: Cell<bool>,
+    retain_contents: bool,
+    backing: RefCell<Option<Backing>>,
+    frame_requested: Cell<bool>,
+    continuous_frames: Cell<bool>,
+    // Whether a NotifyMSC request is outstanding.
+    frame_scheduled: Cell<bool>,
+    pub mapped: Cell<bool>,
+    // The ancestors of an embedded window below the root, starting with its parent.
+    pub ancestors: RefCell<Vec<Window>>,
+    // Whether all ancestors are mapped.
+    pub ancestors_mapped: Cell<bool>,
+    pub obscured: Cell<bool>,
+    // The visibility last reported with Event::Visible. Frames are paused while not visible.
+    visible: Cell<bool>,
+    // The MSC and UST of the last vblank notification.
+    last_vblank: Cell<Option<(u64, u64)>>,
+    refresh_interval: Cell<Duration>,
+    presented_time: Cell<Option<Duration>>,
     pub expose_rects: RefCell<Vec<Rect>>,
     pub event_loop: EventLoop,
     pub handler: RefCell<Box<dyn FnMut(Event) -> Response>>,
 }
 
 impl WindowState {
-    fn init_shm(
-        event_loop_state: &EventLoopState,
-        width: usize,
-        height: usize,
-    ) -> Result<Option<ShmState>> {
-        if !event_loop_state.shm_supported {
-            return Ok(None);
+    fn init_shm(event_loop_state: &EventLoopState, capacity: usize) -> Result<ShmState> {
+        let connection = &event_loop_state.connection;
+        let len = capacity * mem::size_of::<u32>();
+        let seg_id = connection.generate_id()?;
+
+        // The attach requests are checked, since on a forwarded connection the server can't access
+        // our memory even though it supports the extension. If it can't, shared memory is turned
+        // off for all windows, while other failures only affect the current allocation.
+        if event_loop_state.shm_fd_supported {
+            let fd = unsafe {
+                let fd = libc::memfd_create(c"portlight".as_ptr(), libc::MFD_CLOEXEC);
+                if fd == -1 {
+                    return Err(Error::Os(OsError::Message(
+                        "failed to create shared memory file",
+                    )));
+                }
+
+                OwnedFd::from_raw_fd(fd)
+            };
+
+            let ptr = unsafe {
+                if libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) == -1 {
+                    return Err(Error::Os(OsError::Message(
+                        "failed to resize shared memory file",
+                    )));
+                }
+
+                let ptr = libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd.as_raw_fd(),
+                    0,
+                );
+                if ptr == libc::MAP_FAILED {
+                    return Err(Error::Os(OsError::Message(
+                        "failed to map shared memory file",
+                    )));
+                }
+
+                ptr
+            };
+
+            // The server maps the file itself, so the fd is closed once it has been sent.
+            let result = connection.shm_attach_fd(seg_id, fd, false);
+            if let Err(err) = result.map_err(Error::from).and_then(|c| Ok(c.check()?)) {
+                unsafe { libc::munmap(ptr, len) };
+                event_loop_state.shm_supported.set(false);
+                return Err(err);
+            }
+
+            return Ok(ShmState {
+                seg_id,
+                ptr,
+                capacity,
+                memfd: true,
+                pixmap: None,
+                busy: false,
+                fence: None,
+            });
         }
 
         let shm_id = unsafe {
-            let shm_id = libc::shmget(
-                libc::IPC_PRIVATE,
-                width * height * mem::size_of::<u32>(),
-                libc::IPC_CREAT | 0o600,
-            );
+            let shm_id = libc::shmget(libc::IPC_PRIVATE, len, libc::IPC_CREAT | 0o600);
             if shm_id == -1 {
                 return Err(Error::Os(OsError::Message(
                     "failed to create shared memory segment",
@@ -78,25 +282,318 @@ impl WindowState {
             ptr
         };
 
-        let seg_id = event_loop_state.connection.generate_id()?;
-        event_loop_state.connection.shm_attach(seg_id, shm_id as u32, false)?;
+        let result = connection.shm_attach(seg_id, shm_id as u32, false);
+        let result = result.map_err(Error::from).and_then(|c| Ok(c.check()?));
+
+        // Once both we and the server are attached, the segment can be marked for removal, so
+        // that it doesn't outlive the process if it crashes.
+        unsafe { libc::shmctl(shm_id, libc::IPC_RMID, ptr::null_mut()) };
+
+        if let Err(err) = result {
+            unsafe { libc::shmdt(ptr) };
+            event_loop_state.shm_supported.set(false);
+            return Err(err);
+        }
 
-        Ok(Some(ShmState {
-            shm_id,
+        Ok(ShmState {
             seg_id,
             ptr,
-            width,
-            height,
-        }))
+            capacity,
+            memfd: false,
+            pixmap: None,
+            busy: false,
+            fence: None,
+        })
     }
 
-    fn deinit_shm(&self) {
-        if let Some(shm_state) = self.shm_state.take() {
-            let _ = self.event_loop.state.connection.shm_detach(shm_state.seg_id);
+    // With PresentPixmap, a buffer stays in use until the server sends IdleNotify for it, which
+    // may not happen until the next one has been presented, so a third buffer is needed to avoid
+    // waiting.
+    fn shm_buffer_count(&self) -> usize {
+        if self.uses_present_pixmap() {
+            3
+        } else {
+            2
+        }
+    }
+
+    // Shared memory may be turned off after the window is created, after which bitmaps are put
+    // onto the window directly.
+    fn uses_present_pixmap(&self) -> bool {
+        let event_loop_state = &self.event_loop.state;
+        event_loop_state.shm_supported.get()
+            && event_loop_state.shm_pixmaps_supported
+            && self.present_state.borrow().is_some()
+    }
+
+    // Returns the index of a shared memory buffer which can hold the given number of pixels and
+    // which the server is done reading from, or None if shared memory can't be used or all buffers
+    // are busy. The buffer is reallocated if it is too small or much larger than needed.
+    fn acquire_shm(&self, len: usize) -> Result<Option<usize>> {
+        let event_loop_state = &self.event_loop.state;
+        if !event_loop_state.shm_supported.get() {
+            return Ok(None);
+        }
+
+        let count = self.shm_buffer_count();
+        for offset in 0..count {
+            let index = (self.shm_index.get() + offset) % count;
 
-            unsafe {
+            // The buffer is still borrowed if present is called from within with_buffer.
+            let Ok(mut buffer) = self.shm_buffers[index].try_borrow_mut() else {
+                continue;
+            };
+
+            if let Some(shm_state) = &mut *buffer {
+                if shm_state.busy {
+                    continue;
+                }
+
+                if let Some(fence) = shm_state.fence.take() {
+                    event_loop_state.connection.wait_for_reply(fence)?;
+                }
+
+                if len > shm_state.capacity || len < shm_state.capacity / 4 {
+                    if let Some(shm_state) = buffer.take() {
+                        self.free_shm(shm_state);
+                    }
+                }
+            }
+
+            if buffer.is_none() {
+                // Leave some headroom, so that a window which is being resized doesn't need a new
+                // segment for every frame.
+                match Self::init_shm(event_loop_state, len + len / 4) {
+                    Ok(shm_state) => *buffer = Some(shm_state),
+                    // Fall back to PutImage.
+                    Err(_) => return Ok(None),
+                }
+            }
+
+            return Ok(Some(index));
+        }
+
+        Ok(None)
+    }
+
+    // Copies a bitmap from the given shared memory buffer to a drawable, and makes the next buffer
+    // the first one to be tried by acquire_shm.
+    fn put_shm(
+        &self,
+        index: usize,
+        drawable: Drawable,
+        width: usize,
+        height: usize,
+        rects_physical: Option<&[(usize, usize, usize, usize)]>,
+    ) -> Result<()> {
+        let connection = &self.event_loop.state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+        let gc_id = self.gc_id.get().ok_or(Error::WindowClosed)?;
+
+        let mut buffer = self.shm_buffers[index].borrow_mut();
+        let Some(shm_state) = &mut *buffer else {
+            return Ok(());
+        };
+
+        self.shm_index.set((index + 1) % self.shm_buffer_count());
+
+        // Offscreen drawables are written to directly.
+        if drawable == window_id && self.uses_present_pixmap() {
+            // The pixmap has to match the size of the bitmap, since it is presented as a whole.
+            if let Some(pixmap) = shm_state
+                .pixmap
+                .take_if(|pixmap| pixmap.width != width || pixmap.height != height)
+            {
+                connection.free_pixmap(pixmap.id)?;
+            }
+
+            if shm_state.pixmap.is_none() {
+                let id = connection.generate_id()?;
+                connection.shm_create_pixmap(
+                    id,
+                    window_id,
+                    width as u16,
+                    height as u16,
+                    self.depth,
+                    shm_state.seg_id,
+                    0,
+                )?;
+                shm_state.pixmap = Some(ShmPixmap { id, width, height });
+            }
+
+            if let Some(pixmap) = &shm_state.pixmap {
+                // The presentation happens at the next vertical blank, which avoids tearing. The
+                // whole bitmap has been written to the buffer, so damaged rectangles don't matter.
+                connection.present_pixmap(
+                    window_id,
+                    pixmap.id,
+                    0,
+                    x11rb::NONE,
+                    x11rb::NONE,
+                    0,
+                    0,
+                    x11rb::NONE,
+                    x11rb::NONE,
+                    x11rb::NONE,
+                    present::Option::NONE.into(),
+                    0,
+                    0,
+                    0,
+                    &[],
+                )?;
+                shm_state.busy = true;
+            }
+
+            return Ok(());
+        }
+
+        if let Some(rects_physical) = rects_physical {
+            let x_rects: Vec<Rectangle> = rects_physical
+                .iter()
+                .map(|&(x, y, width, height)| Rectangle {
+                    x: x as i16,
+                    y: y as i16,
+                    width: width as u16,
+                    height: height as u16,
+                })
+                .collect();
+            connection.set_clip_rectangles(ClipOrdering::UNSORTED, gc_id, 0, 0, &x_rects)?;
+        }
+
+        connection.shm_put_image(
+            drawable,
+            gc_id,
+            width as u16,
+            height as u16,
+            0,
+            0,
+            width as u16,
+            height as u16,
+            0,
+            0,
+            self.depth,
+            ImageFormat::Z_PIXMAP.into(),
+            false,
+            shm_state.seg_id,
+            0,
+        )?;
+
+        if rects_physical.is_some() {
+            connection.change_gc(gc_id, &ChangeGCAux::new().clip_mask(x11rb::NONE))?;
+        }
+
+        // Requests are processed in order, so the server has finished the ShmPutImage by the time
+        // it replies to this. The reply is collected with wait_for_reply in acquire_shm.
+        let cookie = connection.get_input_focus()?;
+        shm_state.fence = Some(cookie.sequence_number());
+        mem::forget(cookie);
+
+        Ok(())
+    }
+
+    // Blocks until the server releases one of the presented pixmaps, or until IDLE_TIMEOUT has
+    // passed. Other events are queued for the event loop.
+    fn wait_for_idle_shm(&self) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let connection = &event_loop_state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let any_busy = self.shm_buffers.iter().any(|buffer| {
+            let buffer = buffer.try_borrow();
+            buffer.is_ok_and(|buffer| buffer.as_ref().is_some_and(|shm_state| shm_state.busy))
+        });
+        if !any_busy {
+            return Ok(());
+        }
+
+        // An earlier wait may already have queued the notification.
+        let mut found = false;
+        event_loop_state.pending_events.borrow_mut().retain(|event| match event {
+            protocol::Event::PresentIdleNotify(event) if event.window == window_id => {
+                self.handle_pixmap_idle(event.pixmap);
+                found = true;
+                false
+            }
+            _ => true,
+        });
+        if found {
+            return Ok(());
+        }
+
+        connection.flush()?;
+        let deadline = Instant::now() + IDLE_TIMEOUT;
+        loop {
+            while let Some(event) = connection.poll_for_event()? {
+                match event {
+                    protocol::Event::PresentIdleNotify(event) if event.window == window_id => {
+                        self.handle_pixmap_idle(event.pixmap);
+                        return Ok(());
+                    }
+                    event => event_loop_state.pending_events.borrow_mut().push_back(event),
+                }
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Ok(());
+            }
+
+            let mut fds = [libc::pollfd {
+                fd: connection.stream().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            let timeout = timeout.as_micros().div_ceil(1000) as i32;
+            unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as u64, timeout) };
+        }
+    }
+
+    // Called on IdleNotify, once the server no longer needs a presented pixmap.
+    pub fn handle_pixmap_idle(&self, pixmap_id: Pixmap) {
+        for buffer in &self.shm_buffers {
+            // The buffer being written to by with_buffer isn't presented, so it can be skipped.
+            let Ok(mut buffer) = buffer.try_borrow_mut() else {
+                continue;
+            };
+            if let Some(shm_state) = &mut *buffer {
+                if shm_state.pixmap.as_ref().is_some_and(|pixmap| pixmap.id == pixmap_id) {
+                    shm_state.busy = false;
+                }
+            }
+        }
+    }
+
+    fn free_shm(&self, shm_state: ShmState) {
+        let connection = &self.event_loop.state.connection;
+
+        if let Some(fence) = shm_state.fence {
+            connection.discard_reply(
+                fence,
+                RequestKind::HasResponse,
+                DiscardMode::DiscardReplyAndError,
+            );
+        }
+
+        if let Some(pixmap) = shm_state.pixmap {
+            let _ = connection.free_pixmap(pixmap.id);
+        }
+
+        let _ = connection.shm_detach(shm_state.seg_id);
+
+        unsafe {
+            if shm_state.memfd {
+                let len = shm_state.capacity * mem::size_of::<u32>();
+                libc::munmap(shm_state.ptr, len);
+            } else {
                 libc::shmdt(shm_state.ptr);
-                libc::shmctl(shm_state.shm_id, libc::IPC_RMID, ptr::null_mut());
+            }
+        }
+    }
+
+    fn deinit_shm(&self) {
+        for buffer in &self.shm_buffers {
+            if let Some(shm_state) = buffer.take() {
+                self.free_shm(shm_state);
             }
         }
     }
@@ -134,11 +631,36 @@ impl WindowState {
             | EventMask::LEAVE_WINDOW
             | EventMask::POINTER_MOTION
             | EventMask::BUTTON_PRESS
-            | EventMask::BUTTON_RELEASE;
-        let aux = CreateWindowAux::new().event_mask(event_mask);
+            | EventMask::BUTTON_RELEASE
+            | EventMask::KEY_PRESS
+            | EventMask::KEY_RELEASE
+            | EventMask::FOCUS_CHANGE
+            | EventMask::STRUCTURE_NOTIFY
+            | EventMask::VISIBILITY_CHANGE;
+        let mut aux = CreateWindowAux::new().event_mask(event_mask);
+
+        // A window with a different visual than its parent needs its own colormap, and a border
+        // pixel to avoid inheriting the parent's border pixmap.
+        let argb_visual = event_loop_state.argb_visual.filter(|_| options.transparent);
+        let (window_depth, visual, colormap) = if let Some(visual) = argb_visual {
+            let colormap = connection.generate_id()?;
+            connection.create_colormap(ColormapAlloc::NONE, colormap, parent_id, visual)?;
+            aux = aux.colormap(colormap).border_pixel(0).background_pixel(0);
+
+            (32, visual, Some(colormap))
+        } else {
+            (x11rb::COPY_FROM_PARENT as u8, x11rb::COPY_FROM_PARENT, None)
+        };
+
+        // Other windows are assumed to have the depth of the root window.
+        let (depth, pixel_format) = if argb_visual.is_some() {
+            (32, event_loop_state.argb_pixel_format)
+        } else {
+            (24, event_loop_state.pixel_format)
+        };
 
         connection.create_window(
-            x11rb::COPY_FROM_PARENT as u8,
+            window_depth,
             window_id,
             parent_id,
             position_physical.x.round() as i16,
@@ -147,7 +669,7 @@ impl WindowState {
             size_physical.height.round() as u16,
             0,
             WindowClass::INPUT_OUTPUT,
-            x11rb::COPY_FROM_PARENT,
+            visual,
             &aux,
         )?;
 
@@ -170,45 +692,86 @@ impl WindowState {
             window_id,
             event_loop_state.atoms.WM_PROTOCOLS,
             AtomEnum::ATOM,
-            &[event_loop_state.atoms.WM_DELETE_WINDOW],
+            &[
+                event_loop_state.atoms.WM_DELETE_WINDOW,
+                event_loop_state.atoms.WM_TAKE_FOCUS,
+            ],
         )?;
 
+        let size_constraints = SizeConstraints {
+            resizable: options.resizable,
+            min_size: options.min_size,
+            max_size: options.max_size,
+        };
+        size_constraints
+            .size_hints(size_physical, event_loop_state.scale)
+            .set_normal_hints(&**connection, window_id)?;
+
         let gc_id = connection.generate_id()?;
         connection.create_gc(gc_id, window_id, &CreateGCAux::default())?;
 
-        let shm_state = WindowState::init_shm(
-            &event_loop_state,
-            size_physical.width.round() as usize,
-            size_physical.height.round() as usize,
-        )?;
-
         let present_state = if event_loop_state.present_supported {
             let event_id = connection.generate_id()?;
             connection.present_select_input(
                 event_id,
                 window_id,
-                present::EventMask::COMPLETE_NOTIFY,
+                present::EventMask::COMPLETE_NOTIFY | present::EventMask::IDLE_NOTIFY,
             )?;
 
-            connection.present_notify_msc(window_id, 0, 0, 1, 0)?;
-
             Some(PresentState { event_id })
         } else {
             None
         };
 
-        connection.flush()?;
+        event_loop_state.pointer.select_events(connection, window_id)?;
+        event_loop_state.ime.create_context(window_id);
 
         let state = Rc::new(WindowState {
             window_id: Cell::new(Some(window_id)),
+            parent_id: options.parent.map(|_| parent_id),
+            position: Cell::new((
+                position_physical.x.round() as i16,
+                position_physical.y.round() as i16,
+            )),
+            scroll_ignored: Cell::new(false),
+            forwarded_keys: RefCell::new(HashSet::new()),
+            size_constraints,
+            size: Cell::new(Size::new(
+                size_physical.width.round(),
+                size_physical.height.round(),
+            )),
             gc_id: Cell::new(Some(gc_id)),
-            shm_state: RefCell::new(shm_state),
+            depth,
+            colormap,
+            pixel_format,
+            shm_buffers: [RefCell::new(None), RefCell::new(None), RefCell::new(None)],
+            shm_index: Cell::new(0),
+            fallback_buffer: RefCell::new(Vec::new()),
             present_state: RefCell::new(present_state),
+            scaling: Cell::new(Scaling::None),
+            render_state: RefCell::new(None),
+            presented_scaled: Cell::new(false),
+            retain_contents: options.retain_contents,
+            backing: RefCell::new(None),
+            frame_requested: Cell::new(false),
+            continuous_frames: Cell::new(false),
+            frame_scheduled: Cell::new(false),
+            mapped: Cell::new(false),
+            ancestors: RefCell::new(Vec::new()),
+            ancestors_mapped: Cell::new(true),
+            obscured: Cell::new(false),
+            visible: Cell::new(false),
+            last_vblank: Cell::new(None),
+            refresh_interval: Cell::new(event_loop_state.refresh_interval),
+            presented_time: Cell::new(None),
             expose_rects: RefCell::new(Vec::new()),
             event_loop: event_loop.clone(),
             handler: RefCell::new(Box::new(handler)),
         });
 
+        state.watch_ancestors()?;
+        connection.flush()?;
+
         event_loop_state.windows.borrow_mut().insert(window_id, Rc::clone(&state));
 
         Ok(state)
@@ -243,6 +806,33 @@ impl WindowState {
         Ok(size_physical.scale(event_loop_state.scale.recip()))
     }
 
+    pub fn set_size(&self, size: Size) {
+        let _ = self.set_size_inner(size);
+    }
+
+    fn set_size_inner(&self, size: Size) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let connection = &event_loop_state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let size_physical = size.scale(event_loop_state.scale);
+
+        // Otherwise, the window manager would keep a fixed-size window at its old size.
+        if !self.size_constraints.resizable {
+            self.size_constraints
+                .size_hints(size_physical, event_loop_state.scale)
+                .set_normal_hints(&**connection, window_id)?;
+        }
+
+        let aux = ConfigureWindowAux::new()
+            .width(size_physical.width.round() as u32)
+            .height(size_physical.height.round() as u32);
+        connection.configure_window(window_id, &aux)?;
+        connection.flush()?;
+
+        Ok(())
+    }
+
     pub fn scale(&self) -> f64 {
         self.event_loop.state.scale
     }
@@ -261,81 +851,765 @@ impl WindowState {
         let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
         let gc_id = self.gc_id.get().ok_or(Error::WindowClosed)?;
 
-        if let Some(rects) = rects {
-            let mut x_rects = Vec::with_capacity(rects.len());
-            for rect in rects {
-                let rect_physical = rect.scale(event_loop_state.scale);
+        if bitmap.width() == 0 || bitmap.height() == 0 {
+            return Ok(());
+        }
 
-                x_rects.push(Rectangle {
-                    x: rect_physical.x.round() as i16,
-                    y: rect_physical.y.round() as i16,
-                    width: rect_physical.width.round() as u16,
-                    height: rect_physical.height.round() as u16,
-                });
+        // Damaged rectangles in physical pixels, clamped to the bounds of the bitmap.
+        let rects_physical = rects.map(|rects| {
+            rects
+                .iter()
+                .filter_map(|rect| {
+                    let rect_physical = rect.scale(event_loop_state.scale);
+                    let x0 = (rect_physical.x.floor().max(0.0) as usize).min(bitmap.width());
+                    let y0 = (rect_physical.y.floor().max(0.0) as usize).min(bitmap.height());
+                    let x1 = ((rect_physical.x + rect_physical.width).ceil().max(0.0) as usize)
+                        .min(bitmap.width());
+                    let y1 = ((rect_physical.y + rect_physical.height).ceil().max(0.0) as usize)
+                        .min(bitmap.height());
+
+                    if x0 < x1 && y0 < y1 {
+                        Some((x0, y0, x1 - x0, y1 - y0))
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // A scaled bitmap is uploaded to an offscreen pixmap first, and then composited onto the
+        // window. This bypasses the Present extension.
+        let size = self.size.get();
+        let window_size = (size.width as usize, size.height as usize);
+        let scaled = event_loop_state.render_supported
+            && self.scaling.get() != Scaling::None
+            && (bitmap.width(), bitmap.height()) != window_size
+            && window_size.0 > 0
+            && window_size.1 > 0;
+        let scale_source = if scaled {
+            Some(self.scale_source(bitmap.width(), bitmap.height())?)
+        } else {
+            None
+        };
+        self.presented_scaled.set(scaled);
+
+        let len = bitmap.width() * bitmap.height();
+        let mut index = self.acquire_shm(len)?;
+
+        // If all buffers are still waiting to be presented, putting the bitmap directly onto the
+        // window could let an older pending presentation replace it, so wait for one to be freed.
+        // If the server doesn't release one in time, the bitmap is put onto the window anyway.
+        if index.is_none() && self.uses_present_pixmap() {
+            self.wait_for_idle_shm()?;
+            index = self.acquire_shm(len)?;
+        }
+        if let Some(index) = index {
+            if let Some(shm_state) = &*self.shm_buffers[index].borrow() {
+                let row_bytes = bitmap.width() * mem::size_of::<u32>();
+                let data = unsafe {
+                    slice::from_raw_parts_mut(shm_state.ptr as *mut u8, len * mem::size_of::<u32>())
+                };
+                bitmap.copy_to(data, row_bytes, self.pixel_format);
             }
+        }
 
-            connection.set_clip_rectangles(ClipOrdering::UNSORTED, gc_id, 0, 0, &x_rects)?;
+        // A retained window keeps a copy of the bitmap in its backing pixmap. Unless the bitmap is
+        // presented with PresentPixmap, it is uploaded there and then copied to the window. The
+        // offscreen pixmap of a scaled window serves the same purpose.
+        let present_pixmap = index.is_some() && !scaled && self.uses_present_pixmap();
+        let backing = if self.retain_contents && !scaled && !present_pixmap {
+            Some(self.backing_pixmap(bitmap.width(), bitmap.height())?)
+        } else {
+            None
+        };
+
+        let drawable = scale_source.or(backing.map(|(pixmap, _)| pixmap)).unwrap_or(window_id);
+
+        // A new backing pixmap has to be filled completely.
+        let upload_rects = match backing {
+            Some((_, true)) => None,
+            _ => rects_physical.as_deref(),
+        };
+
+        if let Some(index) = index {
+            self.put_shm(
+                index,
+                drawable,
+                bitmap.width(),
+                bitmap.height(),
+                upload_rects,
+            )?;
+        } else if let Some(upload_rects) = upload_rects {
+            for &rect in upload_rects {
+                self.put_image(drawable, gc_id, &bitmap, rect)?;
+            }
+        } else {
+            let rect = (0, 0, bitmap.width(), bitmap.height());
+            self.put_image(drawable, gc_id, &bitmap, rect)?;
+        }
+
+        if scaled {
+            self.composite_scaled((bitmap.width(), bitmap.height()), window_size)?;
+        } else if let Some((pixmap, _)) = backing {
+            let full_rect = [(0, 0, bitmap.width(), bitmap.height())];
+            for &(x, y, width, height) in rects_physical.as_deref().unwrap_or(&full_rect) {
+                let (x, y) = (x as i16, y as i16);
+                connection.copy_area(
+                    pixmap,
+                    window_id,
+                    gc_id,
+                    x,
+                    y,
+                    x,
+                    y,
+                    width as u16,
+                    height as u16,
+                )?;
+            }
+        } else if self.retain_contents && present_pixmap {
+            let shm_pixmap = index.and_then(|index| {
+                let buffer = self.shm_buffers[index].borrow();
+                buffer.as_ref()?.pixmap.as_ref().map(|pixmap| pixmap.id)
+            });
+            if let Some(shm_pixmap) = shm_pixmap {
+                let (pixmap, _) = self.backing_pixmap(bitmap.width(), bitmap.height())?;
+                connection.copy_area(
+                    shm_pixmap,
+                    pixmap,
+                    gc_id,
+                    0,
+                    0,
+                    0,
+                    0,
+                    bitmap.width() as u16,
+                    bitmap.height() as u16,
+                )?;
+            }
+        }
+
+        connection.flush()?;
+
+        Ok(())
+    }
+
+    pub fn capture(&self) -> Result<OwnedBitmap> {
+        let connection = &self.event_loop.state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        // The cached size lags behind until the ConfigureNotify for a resize arrives, and reading
+        // outside the window fails.
+        let geometry = connection.get_geometry(window_id)?.reply()?;
+        let (width, height) = (geometry.width as usize, geometry.height as usize);
+        let row_bytes = width * mem::size_of::<u32>();
+        let format = if self.depth == 32 {
+            PixelFormat::Argb
+        } else {
+            PixelFormat::Xrgb
+        };
+
+        // GetImage fails for empty rectangles.
+        if width == 0 || height == 0 {
+            let bitmap = Bitmap::from_bytes(&[], width, height, row_bytes, self.pixel_format);
+            return Ok(OwnedBitmap::from_bitmap(bitmap, format));
         }
 
-        if let Some(ref shm_state) = *self.shm_state.borrow() {
-            // SAFETY: ptr is page-aligned and thus u32-aligned
-            let data = unsafe {
-                slice::from_raw_parts_mut(
-                    shm_state.ptr as *mut u32,
-                    shm_state.width * shm_state.height * std::mem::size_of::<u32>(),
+        // A temporary segment is used, so that the buffers used for presenting keep their contents
+        // and size. Failing to allocate it only affects this capture.
+        let shm_state = if self.event_loop.state.shm_supported.get() {
+            Self::init_shm(&self.event_loop.state, width * height).ok()
+        } else {
+            None
+        };
+        if let Some(shm_state) = shm_state {
+            let result = connection
+                .shm_get_image(
+                    window_id,
+                    0,
+                    0,
+                    width as u16,
+                    height as u16,
+                    !0,
+                    ImageFormat::Z_PIXMAP.into(),
+                    shm_state.seg_id,
+                    0,
                 )
-            };
+                .map_err(Error::from)
+                .and_then(|cookie| Ok(cookie.reply()?))
+                .map(|_| {
+                    let data = unsafe {
+                        slice::from_raw_parts(shm_state.ptr as *const u8, row_bytes * height)
+                    };
+                    let bitmap =
+                        Bitmap::from_bytes(data, width, height, row_bytes, self.pixel_format);
+                    OwnedBitmap::from_bitmap(bitmap, format)
+                });
+            self.free_shm(shm_state);
 
-            let copy_width = bitmap.width().min(shm_state.width);
-            let copy_height = bitmap.height().min(shm_state.height);
-            for row in 0..copy_height {
-                let src = &bitmap.data()[row * bitmap.width()..row * bitmap.width() + copy_width];
-                let dst = &mut data[row * shm_state.width..row * shm_state.width + copy_width];
-                dst.copy_from_slice(src);
-            }
+            return result;
+        }
 
-            connection.shm_put_image(
+        let reply = connection
+            .get_image(
+                ImageFormat::Z_PIXMAP,
                 window_id,
-                gc_id,
-                shm_state.width as u16,
-                shm_state.height as u16,
-                0,
-                0,
-                shm_state.width as u16,
-                shm_state.height as u16,
                 0,
                 0,
-                24,
-                ImageFormat::Z_PIXMAP.into(),
-                false,
-                shm_state.seg_id,
-                0,
+                width as u16,
+                height as u16,
+                !0,
+            )?
+            .reply()?;
+        let bitmap = Bitmap::from_bytes(&reply.data, width, height, row_bytes, self.pixel_format);
+
+        Ok(OwnedBitmap::from_bitmap(bitmap, format))
+    }
+
+    pub fn scroll_contents(&self, rect: Rect, delta: Point) {
+        let _ = self.scroll_contents_inner(rect, delta);
+    }
+
+    fn scroll_contents_inner(&self, rect: Rect, delta: Point) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let connection = &event_loop_state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+        let gc_id = self.gc_id.get().ok_or(Error::WindowClosed)?;
+
+        // PresentPixmap always presents whole bitmaps, so there is nothing to gain.
+        if self.uses_present_pixmap() && !self.presented_scaled.get() {
+            return Ok(());
+        }
+
+        // Scaled bitmaps are scrolled in the offscreen pixmap, in bitmap coordinates, which is
+        // composited to the window by the next present. Retained windows are scrolled in their
+        // backing pixmap, which is then copied to the window.
+        let drawable = match (&*self.render_state.borrow(), &*self.backing.borrow()) {
+            (
+                Some(RenderState {
+                    source: Some(source),
+                    ..
+                }),
+                _,
+            ) if self.presented_scaled.get() => source.pixmap,
+            (_, Some(backing)) if self.retain_contents => backing.pixmap,
+            _ => window_id,
+        };
+
+        let rect_physical = rect.scale(event_loop_state.scale);
+        let x0 = rect_physical.x.round() as i32;
+        let y0 = rect_physical.y.round() as i32;
+        let x1 = (rect_physical.x + rect_physical.width).round() as i32;
+        let y1 = (rect_physical.y + rect_physical.height).round() as i32;
+        let dx = (delta.x * event_loop_state.scale).round() as i32;
+        let dy = (delta.y * event_loop_state.scale).round() as i32;
+
+        // The part of the rectangle which stays inside it after moving.
+        let width = x1 - x0 - dx.abs();
+        let height = y1 - y0 - dy.abs();
+        if width <= 0 || height <= 0 {
+            return Ok(());
+        }
+
+        let src_x = x0 + (-dx).max(0);
+        let src_y = y0 + (-dy).max(0);
+        connection.copy_area(
+            drawable,
+            drawable,
+            gc_id,
+            src_x as i16,
+            src_y as i16,
+            (src_x + dx) as i16,
+            (src_y + dy) as i16,
+            width as u16,
+            height as u16,
+        )?;
+        if drawable != window_id && !self.presented_scaled.get() {
+            connection.copy_area(
+                drawable,
+                window_id,
+                gc_id,
+                (src_x + dx) as i16,
+                (src_y + dy) as i16,
+                (src_x + dx) as i16,
+                (src_y + dy) as i16,
+                width as u16,
+                height as u16,
             )?;
+        }
+        connection.flush()?;
+
+        Ok(())
+    }
+
+    pub fn set_scaling(&self, scaling: Scaling) {
+        self.scaling.set(scaling);
+    }
+
+    pub fn with_buffer<F: FnOnce(&mut BitmapMut)>(&self, f: F) {
+        let _ = self.with_buffer_inner(f);
+    }
+
+    fn with_buffer_inner<F: FnOnce(&mut BitmapMut)>(&self, f: F) -> Result<()> {
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let size = self.size.get();
+        let width = size.width as usize;
+        let height = size.height as usize;
+        let len = width * height;
+
+        let format = if self.depth == 32 {
+            PixelFormat::Argb
         } else {
-            let (_, bytes, _) = unsafe { bitmap.data().align_to::<u8>() };
+            PixelFormat::Xrgb
+        };
+
+        // The buffer can only be handed out directly if the server uses the same format. Retained
+        // windows go through present_inner, which keeps a copy of the contents.
+        if len > 0 && self.pixel_format == format && !self.retain_contents {
+            if let Some(index) = self.acquire_shm(len)? {
+                if let Some(shm_state) = &*self.shm_buffers[index].borrow() {
+                    // SAFETY: ptr is page-aligned and thus u32-aligned, and the server is done
+                    // reading from the buffer.
+                    let data = unsafe { slice::from_raw_parts_mut(shm_state.ptr as *mut u32, len) };
+                    f(&mut BitmapMut::with_format(data, width, height, format));
+                }
+
+                // The buffer is the size of the window, so it is never scaled.
+                self.presented_scaled.set(false);
+                self.put_shm(index, window_id, width, height, None)?;
+                self.event_loop.state.connection.flush()?;
+
+                return Ok(());
+            }
+        }
+
+        let mut data = self.fallback_buffer.take();
+        data.resize(len, 0);
+        let mut bitmap = BitmapMut::with_format(&mut data, width, height, format);
+        f(&mut bitmap);
+        let result = self.present_inner(bitmap.as_bitmap(), None);
+        self.fallback_buffer.replace(data);
+
+        result
+    }
+
+    // Returns the offscreen pixmap which scaled bitmaps of the given size are uploaded to, creating
+    // it along with the pictures used for compositing if needed.
+    fn scale_source(&self, width: usize, height: usize) -> Result<Pixmap> {
+        let connection = &self.event_loop.state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let mut render_state = self.render_state.borrow_mut();
+        let render_state = match &mut *render_state {
+            Some(render_state) => render_state,
+            None => {
+                let visual = connection.get_window_attributes(window_id)?.reply()?.visual;
+                let formats = connection.render_query_pict_formats()?.reply()?;
+                let format = formats
+                    .screens
+                    .iter()
+                    .flat_map(|screen| &screen.depths)
+                    .flat_map(|depth| &depth.visuals)
+                    .find(|pict_visual| pict_visual.visual == visual)
+                    .map(|pict_visual| pict_visual.format)
+                    .ok_or(Error::Os(OsError::Message(
+                        "no picture format for window visual",
+                    )))?;
+
+                let window_picture = connection.generate_id()?;
+                connection.render_create_picture(
+                    window_picture,
+                    window_id,
+                    format,
+                    &CreatePictureAux::new(),
+                )?;
+
+                render_state.insert(RenderState {
+                    format,
+                    window_picture,
+                    source: None,
+                })
+            }
+        };
+
+        if let Some(source) = render_state
+            .source
+            .take_if(|source| source.width != width || source.height != height)
+        {
+            connection.render_free_picture(source.picture)?;
+            connection.free_pixmap(source.pixmap)?;
+        }
+
+        if let Some(source) = &render_state.source {
+            return Ok(source.pixmap);
+        }
+
+        let pixmap = connection.generate_id()?;
+        connection.create_pixmap(self.depth, pixmap, window_id, width as u16, height as u16)?;
+        let picture = connection.generate_id()?;
+        connection.render_create_picture(
+            picture,
+            pixmap,
+            render_state.format,
+            &CreatePictureAux::new(),
+        )?;
+        connection.render_set_picture_filter(picture, b"bilinear", &[])?;
+
+        render_state.source = Some(ScaleSource {
+            pixmap,
+            picture,
+            width,
+            height,
+        });
+
+        Ok(pixmap)
+    }
+
+    // Draws the offscreen pixmap onto the window according to the scaling policy. Parts of the
+    // window not covered by the bitmap are cleared.
+    fn composite_scaled(
+        &self,
+        bitmap_size: (usize, usize),
+        window_size: (usize, usize),
+    ) -> Result<()> {
+        let connection = &self.event_loop.state.connection;
+
+        let render_state = self.render_state.borrow();
+        let Some(render_state) = &*render_state else {
+            return Ok(());
+        };
+        let Some(source) = &render_state.source else {
+            return Ok(());
+        };
+
+        let (x, y, width, height) = scaled_rect(self.scaling.get(), bitmap_size, window_size);
+
+        // The transform maps window coordinates to bitmap coordinates.
+        let scale_x = bitmap_size.0 as f64 / width;
+        let scale_y = bitmap_size.1 as f64 / height;
+        let to_fixed = |value: f64| (value * 65536.0).round() as Fixed;
+        let transform = Transform {
+            matrix11: to_fixed(scale_x),
+            matrix12: 0,
+            matrix13: to_fixed(-x * scale_x),
+            matrix21: 0,
+            matrix22: to_fixed(scale_y),
+            matrix23: to_fixed(-y * scale_y),
+            matrix31: 0,
+            matrix32: 0,
+            matrix33: to_fixed(1.0),
+        };
+        connection.render_set_picture_transform(source.picture, transform)?;
+
+        connection.render_composite(
+            PictOp::SRC,
+            source.picture,
+            x11rb::NONE,
+            render_state.window_picture,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            window_size.0 as u16,
+            window_size.1 as u16,
+        )?;
+
+        Ok(())
+    }
+
+    fn free_render_state(&self) {
+        if let Some(render_state) = self.render_state.take() {
+            let connection = &self.event_loop.state.connection;
+            if let Some(source) = render_state.source {
+                let _ = connection.render_free_picture(source.picture);
+                let _ = connection.free_pixmap(source.pixmap);
+            }
+            let _ = connection.render_free_picture(render_state.window_picture);
+        }
+    }
+
+    // Returns the backing pixmap of a retained window, recreating it if its size doesn't match the
+    // bitmap, and whether it is new.
+    fn backing_pixmap(&self, width: usize, height: usize) -> Result<(Pixmap, bool)> {
+        let connection = &self.event_loop.state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let mut backing = self.backing.borrow_mut();
+        if let Some(backing) = &*backing {
+            if backing.width == width && backing.height == height {
+                return Ok((backing.pixmap, false));
+            }
+        }
+
+        if let Some(old) = backing.take() {
+            connection.free_pixmap(old.pixmap)?;
+        }
+
+        let pixmap = connection.generate_id()?;
+        connection.create_pixmap(self.depth, pixmap, window_id, width as u16, height as u16)?;
+        *backing = Some(Backing {
+            pixmap,
+            width,
+            height,
+        });
+
+        Ok((pixmap, true))
+    }
+
+    // Repaints exposed parts of a retained window from its last presented bitmap. Returns false if
+    // the handler has to redraw them instead.
+    pub fn repaint_retained(&self, rects_physical: &[Rect]) -> bool {
+        self.retain_contents && self.repaint_retained_inner(rects_physical).unwrap_or(false)
+    }
+
+    fn repaint_retained_inner(&self, rects_physical: &[Rect]) -> Result<bool> {
+        let connection = &self.event_loop.state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+        let gc_id = self.gc_id.get().ok_or(Error::WindowClosed)?;
+
+        if self.presented_scaled.get() {
+            let source_size = match &*self.render_state.borrow() {
+                Some(RenderState {
+                    source: Some(source),
+                    ..
+                }) => (source.width, source.height),
+                _ => return Ok(false),
+            };
+            let size = self.size.get();
+            self.composite_scaled(source_size, (size.width as usize, size.height as usize))?;
+            connection.flush()?;
+
+            return Ok(true);
+        }
+
+        let backing = self.backing.borrow();
+        let Some(backing) = &*backing else {
+            return Ok(false);
+        };
+
+        // Parts of the window outside of the last bitmap have never been drawn.
+        let inside = rects_physical.iter().all(|rect| {
+            rect.x + rect.width <= backing.width as f64
+                && rect.y + rect.height <= backing.height as f64
+        });
+        if !inside {
+            return Ok(false);
+        }
+
+        for rect in rects_physical {
+            let (x, y) = (rect.x as i16, rect.y as i16);
+            connection.copy_area(
+                backing.pixmap,
+                window_id,
+                gc_id,
+                x,
+                y,
+                x,
+                y,
+                rect.width as u16,
+                rect.height as u16,
+            )?;
+        }
+        connection.flush()?;
+
+        Ok(true)
+    }
+
+    // Uploads part of a bitmap without shared memory. The image is split into bands of rows, since
+    // a large image would exceed the maximum request length.
+    fn put_image(
+        &self,
+        drawable: Drawable,
+        gc_id: Gcontext,
+        bitmap: &Bitmap,
+        rect: (usize, usize, usize, usize),
+    ) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let connection = &event_loop_state.connection;
+        let (x, y, width, height) = rect;
+
+        // The size of a PutImage request without its data.
+        const HEADER_BYTES: usize = 24;
+        let row_bytes = width * mem::size_of::<u32>();
+        let max_rows = (connection.maximum_request_bytes() - HEADER_BYTES) / row_bytes;
+        let band_height = max_rows.clamp(1, height);
+
+        let mut band_data = Vec::new();
+        for band_y in (y..y + height).step_by(band_height) {
+            let band_end = (band_y + band_height).min(y + height);
+
+            // Bands which are contiguous in the bitmap and already in the server's format can be
+            // sent without copying.
+            let band = bitmap.crop(x, band_y, width, band_end - band_y);
+            let bytes = if let Some(bytes) = band.packed(self.pixel_format) {
+                bytes
+            } else {
+                band_data.resize(row_bytes * band.height(), 0);
+                band.copy_to(&mut band_data, row_bytes, self.pixel_format);
+                &band_data[..]
+            };
+
             connection.put_image(
                 ImageFormat::Z_PIXMAP,
-                window_id,
+                drawable,
                 gc_id,
-                bitmap.width() as u16,
-                bitmap.height() as u16,
+                width as u16,
+                (band_end - band_y) as u16,
+                x as i16,
+                band_y as i16,
                 0,
-                0,
-                0,
-                24,
+                self.depth,
                 bytes,
             )?;
         }
 
-        if rects.is_some() {
-            connection.set_clip_rectangles(ClipOrdering::UNSORTED, gc_id, 0, 0, &[])?;
+        Ok(())
+    }
+
+    pub fn request_frame(&self) {
+        self.frame_requested.set(true);
+        let _ = self.schedule_frame();
+    }
+
+    pub fn set_continuous_frames(&self, continuous: bool) {
+        self.continuous_frames.set(continuous);
+        let _ = self.schedule_frame();
+    }
+
+    // Hosts often hide embedded windows by unmapping their parent or one of its ancestors, which
+    // doesn't generate any events for the child, so every ancestor is watched. Windows belonging
+    // to this connection already report this. The chain is walked again when one of the windows
+    // in it is reparented.
+    pub fn watch_ancestors(&self) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let connection = &event_loop_state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        if self.parent_id.is_none() {
+            return Ok(());
+        }
+
+        let root = connection.setup().roots[event_loop_state.screen_index].root;
+        let mut ancestors = Vec::new();
+        let mut ancestor = connection.query_tree(window_id)?.reply()?.parent;
+        while ancestor != root && ancestor != x11rb::NONE {
+            ancestors.push(ancestor);
+            ancestor = connection.query_tree(ancestor)?.reply()?.parent;
         }
 
-        connection.flush()?;
+        let old_ancestors = self.ancestors.replace(ancestors);
+        for &ancestor in &*self.ancestors.borrow() {
+            let own = event_loop_state.windows.borrow().contains_key(&ancestor);
+            if !own && !old_ancestors.contains(&ancestor) {
+                let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY);
+                connection.change_window_attributes(ancestor, &aux)?;
+            }
+        }
+        self.unwatch_ancestors(&old_ancestors);
+
+        self.update_ancestors_mapped()
+    }
+
+    // Stops watching windows which are no longer an ancestor of any of our windows.
+    fn unwatch_ancestors(&self, ancestors: &[Window]) {
+        let event_loop_state = &self.event_loop.state;
+        let windows = event_loop_state.windows.borrow();
+
+        for &ancestor in ancestors {
+            let watched = windows.contains_key(&ancestor)
+                || self.ancestors.borrow().contains(&ancestor)
+                || windows.values().any(|window| window.ancestors.borrow().contains(&ancestor));
+            if !watched {
+                let aux = ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT);
+                let _ = event_loop_state.connection.change_window_attributes(ancestor, &aux);
+            }
+        }
+    }
+
+    // A window is only viewable if it and all of its ancestors are mapped.
+    pub fn update_ancestors_mapped(&self) -> Result<()> {
+        let parent = self.ancestors.borrow().first().copied();
+        if let Some(parent) = parent {
+            // The parent may have been destroyed in the meantime.
+            let connection = &self.event_loop.state.connection;
+            let attributes = connection.get_window_attributes(parent)?.reply();
+            let viewable =
+                attributes.is_ok_and(|attributes| attributes.map_state == MapState::VIEWABLE);
+            self.ancestors_mapped.set(viewable);
+        }
+
+        Ok(())
+    }
+
+    // Returns the new visibility if it changed since it was last reported.
+    pub fn update_visibility(&self) -> Option<bool> {
+        let visible = self.mapped.get() && self.ancestors_mapped.get() && !self.obscured.get();
+        (self.visible.replace(visible) != visible).then_some(visible)
+    }
+
+    // Asks the server to notify us at the next vblank, or the frame clock to tick if the server
+    // doesn't support Present, if a frame is wanted and none is scheduled yet.
+    pub fn schedule_frame(&self) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let connection = &event_loop_state.connection;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let wanted = self.frame_requested.get() || self.continuous_frames.get();
+        if !wanted || !self.visible.get() || self.frame_scheduled.get() {
+            return Ok(());
+        }
+
+        if self.present_state.borrow().is_some() {
+            connection.present_notify_msc(window_id, 0, 0, 1, 0)?;
+            connection.flush()?;
+        } else if let Some(frame_clock) = &event_loop_state.frame_clock {
+            frame_clock.schedule();
+        } else {
+            return Ok(());
+        }
+        self.frame_scheduled.set(true);
 
         Ok(())
     }
 
+    // Called when a vblank notification arrives or the frame clock ticks, with the number and
+    // time in microseconds of the refresh. Returns the frame to send, if one is wanted.
+    pub fn take_frame(&self, msc: u64, ust: u64) -> Option<FrameInfo> {
+        if !self.frame_scheduled.replace(false) {
+            return None;
+        }
+
+        // The previous notification may have been several refreshes ago.
+        if let Some((last_msc, last_ust)) = self.last_vblank.replace(Some((msc, ust))) {
+            if msc > last_msc && ust > last_ust {
+                let interval = (ust - last_ust) / (msc - last_msc);
+                self.refresh_interval.set(Duration::from_micros(interval));
+            }
+        }
+
+        // A request made while the window is hidden is kept until it is visible again.
+        if !self.visible.get() {
+            return None;
+        }
+
+        if !self.frame_requested.take() && !self.continuous_frames.get() {
+            return None;
+        }
+
+        let refresh_interval = self.refresh_interval.get();
+        Some(FrameInfo {
+            target_time: Duration::from_micros(ust) + refresh_interval,
+            presented_time: self.presented_time.get(),
+            refresh_interval,
+            sequence: msc + 1,
+        })
+    }
+
+    pub fn handle_pixmap_complete(&self, ust: u64) {
+        self.presented_time.set(Some(Duration::from_micros(ust)));
+    }
+
     pub fn set_cursor(&self, cursor: Cursor) {
         let _ = self.set_cursor_inner(cursor);
     }
@@ -343,39 +1617,9 @@ impl WindowState {
     fn set_cursor_inner(&self, cursor: Cursor) -> Result<()> {
         let event_loop_state = &self.event_loop.state;
         let connection = &event_loop_state.connection;
-        let cursor_cache = &event_loop_state.cursor_cache;
         let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
 
-        let cursor_id = if let Some(cursor_id) = cursor_cache.borrow_mut().get(&cursor) {
-            *cursor_id
-        } else {
-            if cursor == Cursor::None {
-                let cursor_id = connection.generate_id()?;
-                let pixmap_id = connection.generate_id()?;
-                let root = connection.setup().roots[event_loop_state.screen_index].root;
-                connection.create_pixmap(1, pixmap_id, root, 1, 1)?;
-                connection
-                    .create_cursor(cursor_id, pixmap_id, pixmap_id, 0, 0, 0, 0, 0, 0, 0, 0)?;
-                connection.free_pixmap(pixmap_id)?;
-
-                cursor_id
-            } else {
-                let cursor_name = match cursor {
-                    Cursor::Arrow => "left_ptr",
-                    Cursor::Crosshair => "crosshair",
-                    Cursor::Hand => "hand2",
-                    Cursor::IBeam => "text",
-                    Cursor::No => "crossed_circle",
-                    Cursor::SizeNs => "v_double_arrow",
-                    Cursor::SizeWe => "h_double_arrow",
-                    Cursor::SizeNesw => "fd_double_arrow",
-                    Cursor::SizeNwse => "bd_double_arrow",
-                    Cursor::Wait => "watch",
-                    Cursor::None => unreachable!(),
-                };
-                event_loop_state.cursor_handle.load_cursor(connection, cursor_name)?
-            }
-        };
+        let cursor_id = event_loop_state.get_cursor(cursor)?;
 
         connection.change_window_attributes(
             window_id,
@@ -405,9 +1649,93 @@ impl WindowState {
         }
     }
 
+    pub fn set_ime_cursor_area(&self, rect: Rect) {
+        if let Some(window_id) = self.window_id.get() {
+            let event_loop_state = &self.event_loop.state;
+            let rect_physical = rect.scale(event_loop_state.scale);
+
+            // XIM positions the candidate window relative to the baseline of the text.
+            event_loop_state.ime.set_cursor_position(
+                window_id,
+                rect_physical.x.round() as i16,
+                (rect_physical.y + rect_physical.height).round() as i16,
+            );
+            let _ = event_loop_state.connection.flush();
+        }
+    }
+
+    pub fn focus(&self) {
+        let _ = self.focus_inner();
+    }
+
+    fn focus_inner(&self) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        // If the window is hidden or closed, the focus reverts to its parent, so an embedded
+        // window hands the focus back to its host.
+        event_loop_state.connection.set_input_focus(
+            InputFocus::PARENT,
+            window_id,
+            event_loop_state.user_time.get(),
+        )?;
+        event_loop_state.connection.flush()?;
+
+        Ok(())
+    }
+
+    pub fn has_focus(&self) -> bool {
+        let focused_window = self.event_loop.state.focused_window.get();
+        self.window_id.get().is_some() && focused_window == self.window_id.get()
+    }
+
+    pub fn lock_pointer(&self) {
+        let _ = self.lock_pointer_inner();
+    }
+
+    fn lock_pointer_inner(&self) -> Result<()> {
+        let event_loop_state = &self.event_loop.state;
+        let window_id = self.window_id.get().ok_or(Error::WindowClosed)?;
+
+        let cursor_id = event_loop_state.get_cursor(Cursor::None)?;
+        event_loop_state
+            .pointer
+            .lock(&event_loop_state.connection, window_id, cursor_id)?;
+        event_loop_state.connection.flush()?;
+
+        Ok(())
+    }
+
+    pub fn unlock_pointer(&self) {
+        if let Some(window_id) = self.window_id.get() {
+            let event_loop_state = &self.event_loop.state;
+            if event_loop_state.pointer.locked_window() == Some(window_id) {
+                let _ = event_loop_state.pointer.unlock(&event_loop_state.connection);
+                let _ = event_loop_state.connection.flush();
+            }
+        }
+    }
+
     pub fn close(&self) {
+        self.unlock_pointer();
+
         if let Some(window_id) = self.window_id.take() {
+            let pointer = &self.event_loop.state.pointer;
+            if pointer.captured_window() == Some(window_id) {
+                let _ = pointer.end_capture(&self.event_loop.state.connection);
+            }
+            if pointer.hovered_window() == Some(window_id) {
+                pointer.set_hovered_window(None);
+            }
+            if self.event_loop.state.focused_window.get() == Some(window_id) {
+                self.event_loop.state.focused_window.set(None);
+            }
+
             self.event_loop.state.windows.borrow_mut().remove(&window_id);
+            self.event_loop.state.ime.destroy_context(window_id);
+
+            let ancestors = self.ancestors.take();
+            self.unwatch_ancestors(&ancestors);
 
             let connection = &self.event_loop.state.connection;
 
@@ -416,6 +1744,11 @@ impl WindowState {
             }
 
             self.deinit_shm();
+            self.free_render_state();
+
+            if let Some(backing) = self.backing.take() {
+                let _ = connection.free_pixmap(backing.pixmap);
+            }
 
             if let Some(present_state) = self.present_state.take() {
                 let _ = connection.present_select_input(
@@ -426,6 +1759,10 @@ impl WindowState {
             }
 
             let _ = connection.destroy_window(window_id);
+
+            if let Some(colormap) = self.colormap {
+                let _ = connection.free_colormap(colormap);
+            }
         }
 
         let _ = self.event_loop.state.connection.flush();
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _};
+use x11rb::rust_connection::RustConnection;
+
+use crate::Result;
+
+// Settings published by the desktop environment according to the XSETTINGS specification
+// (https://specifications.freedesktop.org/xsettings-spec/). Only integer settings are kept.
+pub struct XSettings {
+    integers: HashMap<String, i32>,
+}
+
+impl XSettings {
+    pub fn new(connection: &RustConnection, screen_index: usize) -> Result<XSettings> {
+        let integers = Self::read(connection, screen_index)?.unwrap_or_default();
+
+        Ok(XSettings { integers })
+    }
+
+    fn read(
+        connection: &RustConnection,
+        screen_index: usize,
+    ) -> Result<Option<HashMap<String, i32>>> {
+        let selection_name = format!("_XSETTINGS_S{}", screen_index);
+        let selection = connection.intern_atom(false, selection_name.as_bytes())?.reply()?.atom;
+        let property = connection.intern_atom(false, b"_XSETTINGS_SETTINGS")?.reply()?.atom;
+
+        let owner = connection.get_selection_owner(selection)?.reply()?.owner;
+        if owner == x11rb::NONE {
+            return Ok(None);
+        }
+
+        // The owner may have gone away in the meantime, which is not an error.
+        let Ok(reply) = connection
+            .get_property(false, owner, property, AtomEnum::ANY, 0, u32::MAX)?
+            .reply()
+        else {
+            return Ok(None);
+        };
+
+        Ok(parse(&reply.value))
+    }
+
+    pub fn get_integer(&self, name: &str) -> Option<i32> {
+        self.integers.get(name).copied()
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Some(bytes)
+    }
+
+    fn pad(&mut self, len: usize) -> Option<()> {
+        self.bytes((4 - len % 4) % 4).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?.try_into().ok()?;
+        if self.big_endian {
+            Some(u16::from_be_bytes(bytes))
+        } else {
+            Some(u16::from_le_bytes(bytes))
+        }
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?.try_into().ok()?;
+        if self.big_endian {
+            Some(u32::from_be_bytes(bytes))
+        } else {
+            Some(u32::from_le_bytes(bytes))
+        }
+    }
+}
+
+fn parse(data: &[u8]) -> Option<HashMap<String, i32>> {
+    let mut reader = Reader {
+        data,
+        big_endian: false,
+    };
+
+    reader.big_endian = reader.u8()? == 1;
+    reader.bytes(3)?;
+    let _serial = reader.u32()?;
+    let count = reader.u32()?;
+
+    let mut integers = HashMap::new();
+
+    for _ in 0..count {
+        let setting_type = reader.u8()?;
+        reader.bytes(1)?;
+        let name_len = reader.u16()? as usize;
+        let name = String::from_utf8_lossy(reader.bytes(name_len)?).into_owned();
+        reader.pad(name_len)?;
+        let _last_change_serial = reader.u32()?;
+
+        match setting_type {
+            // Integer
+            0 => {
+                integers.insert(name, reader.u32()? as i32);
+            }
+            // String
+            1 => {
+                let len = reader.u32()? as usize;
+                reader.bytes(len)?;
+                reader.pad(len)?;
+            }
+            // Color
+            2 => {
+                reader.bytes(8)?;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(integers)
+}
@@ -1,7 +1,10 @@
 mod backend;
 mod error;
 mod event_loop;
+mod key;
 mod timer;
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+mod timestamp;
 mod window;
 
 #[cfg(feature = "_test")]
@@ -9,8 +12,10 @@ pub mod tests;
 
 pub use error::{Error, Result};
 pub use event_loop::{EventLoop, EventLoopMode, EventLoopOptions};
+pub use key::{Key, KeyCode, KeyEvent, Modifiers};
 pub use timer::Timer;
 pub use window::{
-    Bitmap, Cursor, Event, MouseButton, Point, RawWindow, Rect, Response, Size, Window,
+    Bitmap, BitmapMut, Cursor, Event, FrameInfo, MouseButton, MouseEvent, OwnedBitmap, PixelFormat,
+    Point, RawWindow, Rect, Response, Scaling, ScrollDelta, ScrollEvent, ScrollPhase, Size, Window,
     WindowOptions,
 };
@@ -41,3 +41,211 @@ pub fn leak() {
     assert!(state_weak.upgrade().is_none());
     assert!(window_weak.upgrade().is_none());
 }
+
+#[cfg(target_os = "linux")]
+pub fn capture() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{self, ConnectionExt as _};
+    use x11rb::protocol::xtest::ConnectionExt as _;
+
+    use crate::{Point, RawWindow};
+
+    #[derive(Debug, PartialEq)]
+    enum Recorded {
+        Expose,
+        Enter,
+        Exit,
+        Move(Point),
+        Down,
+        Up,
+    }
+
+    fn pump(event_loop: &EventLoop) {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            event_loop.poll().unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+
+    let event_loop = EventLoop::new().unwrap();
+    let window = WindowOptions::new()
+        .size(Size::new(100.0, 100.0))
+        .open(&event_loop, {
+            let events = Rc::clone(&events);
+            move |event| {
+                let recorded = match event {
+                    Event::Expose(_) => Recorded::Expose,
+                    Event::MouseEnter => Recorded::Enter,
+                    Event::MouseExit => Recorded::Exit,
+                    Event::MouseMove(position) => Recorded::Move(position),
+                    Event::MouseDown(_) => Recorded::Down,
+                    Event::MouseUp(_) => Recorded::Up,
+                    _ => return Response::Ignore,
+                };
+
+                let capture = recorded == Recorded::Down;
+                events.borrow_mut().push(recorded);
+
+                if capture {
+                    Response::Capture
+                } else {
+                    Response::Ignore
+                }
+            }
+        })
+        .unwrap();
+    window.show();
+
+    let start = Instant::now();
+    while !events.borrow().contains(&Recorded::Expose) {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "window was never exposed"
+        );
+        pump(&event_loop);
+    }
+
+    let RawWindow::X11(window_id) = window.as_raw().unwrap() else {
+        unreachable!();
+    };
+    let window_id = window_id as xproto::Window;
+
+    let (connection, screen_index) = x11rb::connect(None).unwrap();
+    let root = connection.setup().roots[screen_index].root;
+    let origin = connection
+        .translate_coordinates(window_id, root, 0, 0)
+        .unwrap()
+        .reply()
+        .unwrap();
+    let geometry = connection.get_geometry(window_id).unwrap().reply().unwrap();
+
+    let fake_input = |event_type: u8, detail: u8, x: i16, y: i16| {
+        connection.xtest_fake_input(event_type, detail, 0, root, x, y, 0).unwrap();
+        connection.flush().unwrap();
+        pump(&event_loop);
+    };
+
+    let inside_x = origin.dst_x + geometry.width as i16 / 2;
+    let inside_y = origin.dst_y + geometry.height as i16 / 2;
+    let outside_x = origin.dst_x + geometry.width as i16 + 20;
+
+    fake_input(xproto::MOTION_NOTIFY_EVENT, 0, inside_x, inside_y);
+    events.borrow_mut().clear();
+
+    fake_input(xproto::BUTTON_PRESS_EVENT, 1, 0, 0);
+    fake_input(xproto::MOTION_NOTIFY_EVENT, 0, outside_x, inside_y);
+    fake_input(xproto::BUTTON_RELEASE_EVENT, 1, 0, 0);
+
+    let events = events.take();
+    let down = events.iter().position(|e| *e == Recorded::Down).expect("no MouseDown");
+    let up = events.iter().position(|e| *e == Recorded::Up).expect("no MouseUp");
+    assert!(down < up);
+
+    // Motion keeps arriving outside the window, without any crossing events.
+    let captured = &events[down + 1..up];
+    assert!(captured.iter().any(|e| matches!(e, Recorded::Move(p) if p.x > 100.0)));
+    assert!(!captured.iter().any(|e| matches!(e, Recorded::Enter | Recorded::Exit)));
+
+    // The deferred MouseExit arrives once the capture ends.
+    assert_eq!(events[up + 1..], [Recorded::Exit]);
+
+    drop(window);
+}
+
+pub fn readback() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::Bitmap;
+
+    let event_loop = EventLoop::new().unwrap();
+    let window = WindowOptions::new()
+        .size(Size::new(64.0, 64.0))
+        .open(&event_loop, |_| Response::Ignore)
+        .unwrap();
+    window.show();
+
+    let scale = window.scale();
+    let width = (64.0 * scale).round() as usize;
+    let height = (64.0 * scale).round() as usize;
+    let pixels: Vec<u32> = (0..width * height)
+        .map(|i| 0xFF000000 | (i % width * 4) as u32 | 0x8000)
+        .collect();
+
+    // Keep presenting until the window is mapped and the contents can be read back.
+    let start = Instant::now();
+    let captured = loop {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "window contents were never captured"
+        );
+
+        window.present(Bitmap::new(&pixels, width, height));
+        event_loop.poll().unwrap();
+        thread::sleep(Duration::from_millis(10));
+
+        if let Ok(captured) = window.capture() {
+            if captured.data() == &pixels[..] {
+                break captured;
+            }
+        }
+    };
+
+    assert_eq!((captured.width(), captured.height()), (width, height));
+
+    drop(window);
+}
+
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+pub fn timestamps() {
+    use std::time::Duration;
+
+    use crate::timestamp::Timestamps;
+
+    const WRAP: u64 = 1 << 32;
+
+    let timestamps = Timestamps::new();
+    let near_end = u32::MAX - 100;
+    assert_eq!(
+        timestamps.extend(near_end),
+        Duration::from_millis(near_end as u64)
+    );
+    assert_eq!(
+        timestamps.extend(u32::MAX - 50),
+        Duration::from_millis(u32::MAX as u64 - 50)
+    );
+
+    // Slightly out of order events don't count as a wraparound.
+    assert_eq!(
+        timestamps.extend(near_end),
+        Duration::from_millis(near_end as u64)
+    );
+
+    // A large backwards jump does.
+    assert_eq!(timestamps.extend(10), Duration::from_millis(WRAP + 10));
+    assert_eq!(timestamps.extend(20), Duration::from_millis(WRAP + 20));
+
+    // A late event from before the wraparound.
+    assert_eq!(
+        timestamps.extend(u32::MAX - 10),
+        Duration::from_millis(u32::MAX as u64 - 10)
+    );
+    assert_eq!(timestamps.extend(30), Duration::from_millis(WRAP + 30));
+
+    // Time keeps increasing across a second wraparound, as long as events keep arriving.
+    let mut time = 30u32;
+    for _ in 0..4 {
+        time = time.wrapping_add(u32::MAX / 4);
+        timestamps.extend(time);
+    }
+    assert_eq!(
+        timestamps.extend(time),
+        Duration::from_millis(2 * WRAP + time as u64)
+    );
+}
@@ -0,0 +1,42 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+// Extends 32-bit millisecond timestamps, which wrap around after about 49.7 days, to 64 bits.
+// Events can arrive slightly out of order, so only a backwards jump by more than half the range
+// counts as a wraparound, and a forwards jump by more than half is a late event from before the
+// most recent one. Every timestamp seen should be passed to extend, since a gap of more than half
+// the range between two of them can't be told apart from a late event.
+pub struct Timestamps {
+    last: Cell<Option<u32>>,
+    wraps: Cell<u64>,
+}
+
+impl Timestamps {
+    pub fn new() -> Timestamps {
+        Timestamps {
+            last: Cell::new(None),
+            wraps: Cell::new(0),
+        }
+    }
+
+    pub fn extend(&self, time: u32) -> Duration {
+        let Some(last) = self.last.get() else {
+            self.last.set(Some(time));
+            return Duration::from_millis(time as u64);
+        };
+
+        let mut wraps = self.wraps.get();
+        if time < last && last - time > u32::MAX / 2 {
+            wraps += 1;
+            self.wraps.set(wraps);
+            self.last.set(Some(time));
+        } else if time > last && time - last <= u32::MAX / 2 {
+            self.last.set(Some(time));
+        } else if time > last {
+            // A late event from before the most recent wraparound.
+            wraps = wraps.saturating_sub(1);
+        }
+
+        Duration::from_millis((wraps << 32) | time as u64)
+    }
+}
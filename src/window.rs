@@ -2,8 +2,9 @@ use std::ffi::{c_ulong, c_void};
 use std::fmt;
 use std::marker::PhantomData;
 use std::rc::Rc;
+use std::time::Duration;
 
-use crate::{backend, EventLoop, Result};
+use crate::{backend, EventLoop, KeyEvent, Modifiers, Result};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Point {
@@ -71,26 +72,99 @@ impl Rect {
     }
 }
 
+/// The layout of a pixel in a [`Bitmap`]. All formats are 4 bytes per pixel, and formats with an
+/// alpha channel use premultiplied alpha.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PixelFormat {
+    /// A `u32` in native byte order of the form `0xXXRRGGBB`, where the top byte is ignored.
+    Xrgb,
+    /// A `u32` in native byte order of the form `0xAARRGGBB`.
+    Argb,
+    /// The bytes red, green, blue and alpha, in that order.
+    Rgba8,
+    /// The bytes blue, green, red and alpha, in that order.
+    Bgra8,
+}
+
+impl PixelFormat {
+    // The byte offsets of the red, green, blue and alpha channels within a pixel.
+    fn channel_offsets(self) -> [usize; 4] {
+        match self {
+            PixelFormat::Xrgb | PixelFormat::Argb => {
+                if cfg!(target_endian = "little") {
+                    [2, 1, 0, 3]
+                } else {
+                    [1, 2, 3, 0]
+                }
+            }
+            PixelFormat::Rgba8 => [0, 1, 2, 3],
+            PixelFormat::Bgra8 => [2, 1, 0, 3],
+        }
+    }
+
+    // Whether pixels can be copied to the given format without changing them. The ignored byte of
+    // Xrgb has to be made opaque when converting to a format with alpha.
+    fn copies_to(self, format: PixelFormat) -> bool {
+        self.channel_offsets() == format.channel_offsets()
+            && (self != PixelFormat::Xrgb || format == PixelFormat::Xrgb)
+    }
+}
+
+const BYTES_PER_PIXEL: usize = 4;
+
+#[derive(Copy, Clone)]
 pub struct Bitmap<'a> {
-    data: &'a [u32],
+    data: &'a [u8],
     width: usize,
     height: usize,
+    // The distance between the starts of consecutive rows, in bytes.
+    stride: usize,
+    format: PixelFormat,
 }
 
 impl<'a> Bitmap<'a> {
+    /// Creates a tightly packed bitmap in [`PixelFormat::Xrgb`].
     #[inline]
     pub fn new(data: &'a [u32], width: usize, height: usize) -> Bitmap<'a> {
         assert!(width * height == data.len(), "invalid bitmap dimensions");
 
+        let (_, bytes, _) = unsafe { data.align_to::<u8>() };
+
+        Bitmap {
+            data: bytes,
+            width,
+            height,
+            stride: width * BYTES_PER_PIXEL,
+            format: PixelFormat::Xrgb,
+        }
+    }
+
+    /// Creates a bitmap from bytes in the given format, with rows `stride` bytes apart.
+    #[inline]
+    pub fn from_bytes(
+        data: &'a [u8],
+        width: usize,
+        height: usize,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Bitmap<'a> {
+        assert!(stride >= width * BYTES_PER_PIXEL, "invalid bitmap stride");
+        assert!(
+            height == 0 || data.len() >= (height - 1) * stride + width * BYTES_PER_PIXEL,
+            "invalid bitmap dimensions"
+        );
+
         Bitmap {
             data,
             width,
             height,
+            stride,
+            format,
         }
     }
 
     #[inline]
-    pub fn data(&self) -> &'a [u32] {
+    pub fn data(&self) -> &'a [u8] {
         self.data
     }
 
@@ -103,6 +177,252 @@ impl<'a> Bitmap<'a> {
     pub fn height(&self) -> usize {
         self.height
     }
+
+    #[inline]
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    #[inline]
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Returns the part of the bitmap covered by `rect`, in pixels, clamped to the bounds of the
+    /// bitmap. No pixels are copied.
+    pub fn sub_view(&self, rect: Rect) -> Bitmap<'a> {
+        let x0 = (rect.x.floor().max(0.0) as usize).min(self.width);
+        let y0 = (rect.y.floor().max(0.0) as usize).min(self.height);
+        let x1 = ((rect.x + rect.width).ceil().max(0.0) as usize).clamp(x0, self.width);
+        let y1 = ((rect.y + rect.height).ceil().max(0.0) as usize).clamp(y0, self.height);
+
+        self.crop(x0, y0, x1 - x0, y1 - y0)
+    }
+
+    pub(crate) fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Bitmap<'a> {
+        assert!(x + width <= self.width && y + height <= self.height);
+
+        let data = if width == 0 || height == 0 {
+            &[]
+        } else {
+            let start = y * self.stride + x * BYTES_PER_PIXEL;
+            &self.data[start..start + (height - 1) * self.stride + width * BYTES_PER_PIXEL]
+        };
+
+        Bitmap {
+            data,
+            width,
+            height,
+            stride: self.stride,
+            format: self.format,
+        }
+    }
+
+    // Returns the data as is if it is tightly packed and already in the given format.
+    pub(crate) fn packed(&self, format: PixelFormat) -> Option<&'a [u8]> {
+        if self.stride == self.width * BYTES_PER_PIXEL && self.format.copies_to(format) {
+            Some(self.data)
+        } else {
+            None
+        }
+    }
+
+    // Copies the pixels to a buffer with rows dst_stride bytes apart, converting them to the given
+    // format.
+    pub(crate) fn copy_to(&self, dst: &mut [u8], dst_stride: usize, format: PixelFormat) {
+        let row_bytes = self.width * BYTES_PER_PIXEL;
+        let src_offsets = self.format.channel_offsets();
+        let dst_offsets = format.channel_offsets();
+        let opaque = self.format == PixelFormat::Xrgb && format != PixelFormat::Xrgb;
+
+        if let Some(data) = self.packed(format) {
+            if dst_stride == row_bytes {
+                dst[..data.len()].copy_from_slice(data);
+                return;
+            }
+        }
+
+        for row in 0..self.height {
+            let src = &self.data[row * self.stride..row * self.stride + row_bytes];
+            let dst = &mut dst[row * dst_stride..row * dst_stride + row_bytes];
+
+            if self.format.copies_to(format) {
+                dst.copy_from_slice(src);
+                continue;
+            }
+
+            let pixels =
+                src.chunks_exact(BYTES_PER_PIXEL).zip(dst.chunks_exact_mut(BYTES_PER_PIXEL));
+            for (src_pixel, dst_pixel) in pixels {
+                for channel in 0..4 {
+                    dst_pixel[dst_offsets[channel]] = src_pixel[src_offsets[channel]];
+                }
+                if opaque {
+                    dst_pixel[dst_offsets[3]] = 0xFF;
+                }
+            }
+        }
+    }
+}
+
+/// How a presented bitmap is drawn when its size differs from the window's size in physical
+/// pixels.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Scaling {
+    /// The bitmap is drawn unscaled in the top left corner of the window.
+    #[default]
+    None,
+    /// The bitmap is stretched to cover the window, ignoring its aspect ratio.
+    Stretch,
+    /// The bitmap is scaled to fit inside the window and centered, preserving its aspect ratio.
+    /// The rest of the window is cleared.
+    Fit,
+    /// The bitmap is scaled to cover the window and centered, preserving its aspect ratio. Parts
+    /// of the bitmap may be cut off.
+    Fill,
+}
+
+pub struct BitmapMut<'a> {
+    data: &'a mut [u32],
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+}
+
+impl<'a> BitmapMut<'a> {
+    #[inline]
+    pub fn new(data: &'a mut [u32], width: usize, height: usize) -> BitmapMut<'a> {
+        BitmapMut::with_format(data, width, height, PixelFormat::Xrgb)
+    }
+
+    // Only u32-based formats make sense here.
+    pub(crate) fn with_format(
+        data: &'a mut [u32],
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+    ) -> BitmapMut<'a> {
+        assert!(width * height == data.len(), "invalid bitmap dimensions");
+
+        BitmapMut {
+            data,
+            width,
+            height,
+            format,
+        }
+    }
+
+    #[inline]
+    pub fn data(&self) -> &[u32] {
+        self.data
+    }
+
+    #[inline]
+    pub fn data_mut(&mut self) -> &mut [u32] {
+        self.data
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    #[inline]
+    pub fn as_bitmap(&self) -> Bitmap<'_> {
+        let (_, bytes, _) = unsafe { self.data.align_to::<u8>() };
+        Bitmap::from_bytes(
+            bytes,
+            self.width,
+            self.height,
+            self.width * BYTES_PER_PIXEL,
+            self.format,
+        )
+    }
+}
+
+/// A bitmap which owns its pixels, as returned by [`Window::capture`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OwnedBitmap {
+    data: Vec<u32>,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+}
+
+impl OwnedBitmap {
+    pub fn new(data: Vec<u32>, width: usize, height: usize) -> OwnedBitmap {
+        assert!(width * height == data.len(), "invalid bitmap dimensions");
+
+        OwnedBitmap {
+            data,
+            width,
+            height,
+            format: PixelFormat::Xrgb,
+        }
+    }
+
+    // Copies a bitmap into a u32-based format. Xrgb pixels are made opaque, so that captures of
+    // the same contents compare equal regardless of what the window system left in the top byte.
+    pub(crate) fn from_bitmap(bitmap: Bitmap, format: PixelFormat) -> OwnedBitmap {
+        let mut data = vec![0u32; bitmap.width() * bitmap.height()];
+        let (_, bytes, _) = unsafe { data.align_to_mut::<u8>() };
+        bitmap.copy_to(bytes, bitmap.width() * BYTES_PER_PIXEL, format);
+
+        if format == PixelFormat::Xrgb {
+            for pixel in &mut data {
+                *pixel |= 0xFF000000;
+            }
+        }
+
+        OwnedBitmap {
+            data,
+            width: bitmap.width(),
+            height: bitmap.height(),
+            format,
+        }
+    }
+
+    #[inline]
+    pub fn data(&self) -> &[u32] {
+        &self.data
+    }
+
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[inline]
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    #[inline]
+    pub fn as_bitmap(&self) -> Bitmap<'_> {
+        let (_, bytes, _) = unsafe { self.data.align_to::<u8>() };
+        Bitmap::from_bytes(
+            bytes,
+            self.width,
+            self.height,
+            self.width * BYTES_PER_PIXEL,
+            self.format,
+        )
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
@@ -114,6 +434,64 @@ pub enum MouseButton {
     Forward,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MouseEvent {
+    pub button: MouseButton,
+    pub position: Point,
+    pub modifiers: Modifiers,
+    /// The number of clicks in quick succession that this event is part of, e.g. 2 for a
+    /// double-click. A button release has the same click count as the press before it.
+    pub click_count: u32,
+    /// The time at which the event occurred. Timestamps are relative to an unspecified starting
+    /// point and are only meaningful in comparison with each other.
+    pub time: Duration,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScrollDelta {
+    /// Scrolling in discrete steps, as with a mouse wheel. A delta of 1.0 corresponds to one
+    /// step. Devices with high-resolution scrolling may produce fractional deltas.
+    Lines(Point),
+    /// Precise scrolling in logical pixels, as with a touchpad. X11 doesn't distinguish touchpads
+    /// from high-resolution wheels, so it only reports `Lines`.
+    Pixels(Point),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ScrollPhase {
+    Begin,
+    Update,
+    End,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ScrollEvent {
+    /// The scroll amount, positive for scrolling up and to the right.
+    pub delta: ScrollDelta,
+    /// The phase of a scroll gesture, for devices which report gestures. Always `None` on X11.
+    pub phase: Option<ScrollPhase>,
+    pub position: Point,
+    pub modifiers: Modifiers,
+    /// The time at which the event occurred. See [`MouseEvent::time`].
+    pub time: Duration,
+}
+
+/// Timing information for [`Event::Frame`]. Timestamps are relative to an unspecified starting
+/// point and are only meaningful in comparison with each other.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameInfo {
+    /// The estimated time at which a bitmap presented in response to this frame appears on screen.
+    pub target_time: Duration,
+    /// The time at which the previously presented bitmap actually appeared on screen, where the
+    /// backend reports it.
+    pub presented_time: Option<Duration>,
+    /// The refresh interval of the display.
+    pub refresh_interval: Duration,
+    /// The number of the display refresh this frame is for. A difference of more than one between
+    /// consecutive frames means that refreshes were skipped.
+    pub sequence: u64,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Cursor {
     Arrow,
@@ -132,21 +510,52 @@ pub enum Cursor {
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Event<'a> {
     Expose(&'a [Rect]),
-    Frame,
+    /// Sent at the display's refresh rate when a frame was requested with
+    /// [`Window::request_frame`] or continuous frames are enabled with
+    /// [`Window::set_continuous_frames`].
+    Frame(FrameInfo),
+    /// The window became visible or hidden, e.g. by being shown, hidden, minimized or fully covered
+    /// by other windows. [`Event::Frame`] is not sent while the window is hidden. Currently only
+    /// sent on X11.
+    Visible(bool),
     Close,
+    /// The window was resized, with the new size in logical units.
+    Resize(Size),
     GainFocus,
     LoseFocus,
     MouseEnter,
     MouseExit,
     MouseMove(Point),
-    MouseDown(MouseButton),
-    MouseUp(MouseButton),
-    Scroll(Point),
+    /// Unaccelerated relative pointer motion, in device-dependent units. Only sent while the
+    /// pointer is locked with [`Window::lock_pointer`].
+    RawMouseMotion(Point),
+    MouseDown(MouseEvent),
+    MouseUp(MouseEvent),
+    Scroll(ScrollEvent),
+    KeyDown(KeyEvent),
+    KeyUp(KeyEvent),
+    /// Text produced by a key press, sent after the corresponding `KeyDown`.
+    TextInput(&'a str),
+    /// The input method's in-progress composition text changed, with the caret position as a byte
+    /// offset. An empty string means composition has ended.
+    ImePreedit(&'a str, Option<usize>),
+    /// The input method finished composing text.
+    ImeCommit(&'a str),
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Response {
+    /// The event was handled.
+    ///
+    /// Returning `Capture` from [`Event::MouseDown`] also captures the pointer until the
+    /// [`Event::MouseUp`] for the same button. While the pointer is captured, `MouseMove` events
+    /// keep arriving when it is outside the window, with positions outside the window bounds, and
+    /// `MouseEnter` and `MouseExit` are not sent. If the pointer is outside the window when the
+    /// button is released, `MouseExit` is sent after the `MouseUp`.
     Capture,
+    /// The event was not handled. Ignored key and scroll events in an embedded window (see
+    /// [`WindowOptions::raw_parent`]) are passed on to the parent window. A key release is passed
+    /// on if and only if its press was, regardless of the response to the release itself.
     Ignore,
 }
 
@@ -162,6 +571,11 @@ pub struct WindowOptions {
     pub(crate) title: String,
     pub(crate) position: Option<Point>,
     pub(crate) size: Size,
+    pub(crate) resizable: bool,
+    pub(crate) transparent: bool,
+    pub(crate) retain_contents: bool,
+    pub(crate) min_size: Option<Size>,
+    pub(crate) max_size: Option<Size>,
     pub(crate) parent: Option<RawWindow>,
 }
 
@@ -171,6 +585,11 @@ impl Default for WindowOptions {
             title: String::new(),
             position: None,
             size: Size::new(0.0, 0.0),
+            resizable: false,
+            transparent: false,
+            retain_contents: false,
+            min_size: None,
+            max_size: None,
             parent: None,
         }
     }
@@ -196,6 +615,47 @@ impl WindowOptions {
         self
     }
 
+    /// Whether the user can resize the window. Defaults to `false`.
+    pub fn resizable(&mut self, resizable: bool) -> &mut Self {
+        self.resizable = resizable;
+        self
+    }
+
+    /// Whether the window has an alpha channel, so that presented bitmaps with alpha can be
+    /// translucent. Defaults to `false`. On X11, this needs a compositor to have any effect. Not
+    /// yet implemented on Windows.
+    pub fn transparent(&mut self, transparent: bool) -> &mut Self {
+        self.transparent = transparent;
+        self
+    }
+
+    /// Whether the window keeps a copy of the last presented bitmap and uses it to repaint exposed
+    /// areas, so that [`Event::Expose`] is only sent when there is nothing to repaint from.
+    /// Defaults to `false`. On macOS, contents are always retained. Not yet implemented on
+    /// Windows.
+    pub fn retain_contents(&mut self, retain_contents: bool) -> &mut Self {
+        self.retain_contents = retain_contents;
+        self
+    }
+
+    /// The minimum size of a resizable window.
+    pub fn min_size(&mut self, min_size: Size) -> &mut Self {
+        self.min_size = Some(min_size);
+        self
+    }
+
+    /// The maximum size of a resizable window.
+    pub fn max_size(&mut self, max_size: Size) -> &mut Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Open the window as a child of an existing native window.
+    ///
+    /// # Safety
+    ///
+    /// `parent` must be a valid window handle for the current platform, and it must remain valid
+    /// for as long as the child window is open.
     pub unsafe fn raw_parent(&mut self, parent: RawWindow) -> &mut Self {
         self.parent = Some(parent);
         self
@@ -231,6 +691,12 @@ impl Window {
         self.state.size()
     }
 
+    /// Sets the size of the window in logical units. `Event::Resize` is sent once the new size
+    /// takes effect.
+    pub fn set_size(&self, size: Size) {
+        self.state.set_size(size);
+    }
+
     pub fn scale(&self) -> f64 {
         self.state.scale()
     }
@@ -239,10 +705,57 @@ impl Window {
         self.state.present(bitmap);
     }
 
+    /// Calls `f` with a buffer the size of the window in physical pixels and then presents it.
+    /// Unlike [`present`](Window::present), this avoids a copy where the backend supports it. The
+    /// initial contents of the buffer are unspecified. The buffer is in [`PixelFormat::Argb`] for
+    /// transparent windows and [`PixelFormat::Xrgb`] otherwise.
+    pub fn with_buffer<F: FnOnce(&mut BitmapMut)>(&self, f: F) {
+        self.state.with_buffer(f);
+    }
+
     pub fn present_partial(&self, bitmap: Bitmap, rects: &[Rect]) {
         self.state.present_partial(bitmap, rects);
     }
 
+    /// Reads back the current contents of the window from the window system, in physical pixels.
+    /// The bitmap is in [`PixelFormat::Argb`] for transparent windows and [`PixelFormat::Xrgb`]
+    /// otherwise. This is slow and mostly useful for testing. On macOS, this returns the last
+    /// presented bitmap.
+    pub fn capture(&self) -> Result<OwnedBitmap> {
+        self.state.capture()
+    }
+
+    /// Moves the already presented pixels inside `rect` by `delta`, without sending them to the
+    /// window system again. Pixels moved outside of `rect` are discarded. This should be followed
+    /// by [`present_partial`](Window::present_partial) with the newly exposed area. Where the
+    /// backend can't copy pixels efficiently, this does nothing, since the whole bitmap is
+    /// presented anyway. This is always the case on macOS, and on X11 when bitmaps are presented
+    /// with the Present extension.
+    pub fn scroll_contents(&self, rect: Rect, delta: Point) {
+        self.state.scroll_contents(rect, delta);
+    }
+
+    /// Sets how presented bitmaps are scaled when their size differs from the window's size, e.g.
+    /// to render at a lower resolution and let the window system upscale. Defaults to
+    /// [`Scaling::None`]. On X11, this requires the RENDER extension. Not yet implemented on
+    /// Windows.
+    pub fn set_scaling(&self, scaling: Scaling) {
+        self.state.set_scaling(scaling);
+    }
+
+    /// Requests a single [`Event::Frame`] at the next display refresh. Calling this from the
+    /// handler for `Frame` requests the following one.
+    pub fn request_frame(&self) {
+        self.state.request_frame();
+    }
+
+    /// Sets whether [`Event::Frame`] is sent on every display refresh, e.g. while animating.
+    /// Defaults to `false`, in which case frames are only sent when requested with
+    /// [`request_frame`](Window::request_frame).
+    pub fn set_continuous_frames(&self, continuous: bool) {
+        self.state.set_continuous_frames(continuous);
+    }
+
     pub fn set_cursor(&self, cursor: Cursor) {
         self.state.set_cursor(cursor);
     }
@@ -251,6 +764,32 @@ impl Window {
         self.state.set_mouse_position(position);
     }
 
+    /// Requests keyboard focus for the window. `GainFocus` is sent once the window has focus.
+    pub fn focus(&self) {
+        self.state.focus();
+    }
+
+    pub fn has_focus(&self) -> bool {
+        self.state.has_focus()
+    }
+
+    /// Hides the pointer and confines it to the window. While the pointer is locked, `MouseMove`
+    /// events are replaced by `RawMouseMotion` events. The pointer is returned to its original
+    /// position when unlocked. Only implemented on X11; elsewhere this does nothing.
+    pub fn lock_pointer(&self) {
+        self.state.lock_pointer();
+    }
+
+    pub fn unlock_pointer(&self) {
+        self.state.unlock_pointer();
+    }
+
+    /// Sets the area of the text caret, so that input method candidate windows can be placed next
+    /// to it. Only implemented on X11; elsewhere this does nothing.
+    pub fn set_ime_cursor_area(&self, rect: Rect) {
+        self.state.set_ime_cursor_area(rect);
+    }
+
     pub fn as_raw(&self) -> Result<RawWindow> {
         self.state.as_raw()
     }
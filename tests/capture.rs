@@ -0,0 +1,11 @@
+//! Like the leak test, this needs to run on the main thread. It drives the pointer with the XTest
+//! extension, so it only runs on X11.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    println!();
+    portlight::tests::capture();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -0,0 +1,6 @@
+//! Like the leak test, this needs to run on the main thread.
+
+fn main() {
+    println!();
+    portlight::tests::readback();
+}
@@ -0,0 +1,10 @@
+//! The timestamp extension is only used by the X11 and Win32 backends.
+
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+fn main() {
+    println!();
+    portlight::tests::timestamps();
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "windows")))]
+fn main() {}